@@ -0,0 +1,510 @@
+//! Textual assembly of compiled code, as written by `debug::disassemble`.
+//!
+//! A file is a sequence of functions, the first of which is the script:
+//!
+//! ```text
+//! ; Comments run from a semicolon to the end of the line.
+//! .function f0 - 0            ; id, name (`-` for the script), arity
+//! .constants
+//!     0: function f1          ; also nil, true, false, numbers and "strings"
+//!     1: 20
+//!     2: nil
+//! .code
+//! .line 1                     ; source line of the instructions that follow
+//!     CLOSURE 0
+//!     DEFINE_GLOBAL inc       ; globals are referred to by name
+//! .line 2
+//!     GET_GLOBAL inc
+//!     CONSTANT 1
+//!     CALL 1
+//!     PRINT
+//!     CONSTANT 2
+//!     RETURN
+//! .end
+//!
+//! .function f1 inc 1
+//! .constants
+//!     0: 1
+//! .code
+//! .line 1
+//!     GET_LOCAL 1
+//!     JUMP_IF_FALSE done      ; jumps refer to labels
+//!     CONSTANT 0
+//!     ADD
+//! done:
+//!     RETURN
+//! .end
+//! ```
+//!
+//! Closures list their captures after `.function`, one per line, as
+//! `.upvalue local <slot>` or `.upvalue upvalue <index>`.
+//!
+//! Instructions are named by `Inst::mnemonic`. Operands that index the
+//! constants, and local, upvalue and argument counts, are numbers. Constants
+//! are listed in index order and must be distinct. Encoding, including the
+//! choice of `_LONG` variants, is the same as for compiled code, and the result
+//! goes through the verifier.
+
+use crate::chunk::{Chunk, Inst, JUMP_LEN, SHORT_MAX};
+use crate::globals::Globals;
+use crate::heap::{Heap, ObjRef};
+use crate::obj::{Function, Obj, UpvalueDesc};
+use crate::value::Value;
+use crate::verify;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Maximum nesting of functions, which keeps assembly from overflowing the
+/// native stack.
+const NESTING_MAX: usize = 256;
+
+/// Why an assembly file was rejected.
+#[derive(Debug, Clone)]
+pub struct AsmError {
+    pub message: String,
+    /// Line of the file at fault, or `None` for errors about the result as a
+    /// whole.
+    pub line: Option<usize>,
+}
+
+impl AsmError {
+    fn at(line: usize, message: String) -> AsmError {
+        AsmError { message, line: Some(line) }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "[line {}] Assembly error: {}", line, self.message),
+            None => write!(f, "Assembly error: {}", self.message),
+        }
+    }
+}
+
+enum ConstantDef {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    /// A function, by id.
+    Function(String),
+}
+
+enum CodeItem {
+    Label(String),
+    Line(usize),
+    Inst { mnemonic: String, operands: Vec<String> },
+}
+
+struct FunctionDef {
+    id: String,
+    name: Option<String>,
+    arity: usize,
+    /// Line of the `.function` directive.
+    line: usize,
+    upvalues: Vec<UpvalueDesc>,
+    constants: Vec<(usize, ConstantDef)>,
+    code: Vec<(usize, CodeItem)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Header,
+    Constants,
+    Code,
+}
+
+/// Assemble `source` into the script function. Strings and nested functions
+/// are allocated on `heap` and globals are resolved in `globals`; like
+/// compilation, assembly never collects.
+pub fn assemble(source: &str, heap: &mut Heap, globals: &mut Globals) -> Result<Function, AsmError> {
+    let defs = parse(source)?;
+
+    let mut by_id = HashMap::new();
+    for (idx, def) in defs.iter().enumerate() {
+        if by_id.insert(def.id.clone(), idx).is_some() {
+            return Err(AsmError::at(def.line, format!("Function {} is defined twice.", def.id)));
+        }
+    }
+
+    let mut builder = Builder { defs: &defs, by_id, built: HashMap::new(), building: HashSet::new(), heap, globals };
+    let function = builder.build(0)?;
+
+    verify::verify(builder.heap, builder.globals, &function)
+        .map_err(|err| AsmError { message: err.to_string(), line: None })?;
+    Ok(function)
+}
+
+/// Drop the comment of a line, if any, leaving semicolons in strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (idx, ch) in line.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..idx],
+            _ => {},
+        }
+    }
+    line
+}
+
+fn parse_number<T: std::str::FromStr>(line: usize, token: &str, what: &str) -> Result<T, AsmError> {
+    token.parse().map_err(|_| AsmError::at(line, format!("Expecting {}, found '{}'.", what, token)))
+}
+
+fn parse(source: &str) -> Result<Vec<FunctionDef>, AsmError> {
+    let mut defs = Vec::new();
+    let mut current: Option<FunctionDef> = None;
+    let mut section = Section::Header;
+
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let text = strip_comment(text).trim();
+        if text.is_empty() {
+            continue;
+        }
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+
+        if tokens[0] == ".function" {
+            if let Some(def) = &current {
+                return Err(AsmError::at(line, format!("Missing .end of function {}.", def.id)));
+            }
+            let [_, id, name, arity] = tokens[..] else {
+                return Err(AsmError::at(line, "Expecting '.function <id> <name> <arity>'.".to_string()));
+            };
+            current = Some(FunctionDef {
+                id: id.to_string(),
+                name: if name == "-" { None } else { Some(name.to_string()) },
+                arity: parse_number(line, arity, "an arity")?,
+                line,
+                upvalues: Vec::new(),
+                constants: Vec::new(),
+                code: Vec::new(),
+            });
+            section = Section::Header;
+            continue;
+        }
+
+        let Some(def) = current.as_mut() else {
+            return Err(AsmError::at(line, "Expecting '.function'.".to_string()));
+        };
+
+        match (tokens[0], section) {
+            (".end", _) => {
+                defs.extend(current.take());
+            },
+            (".upvalue", Section::Header) => {
+                let is_local = match tokens.get(1) {
+                    Some(&"local") if tokens.len() == 3 => true,
+                    Some(&"upvalue") if tokens.len() == 3 => false,
+                    _ => return Err(AsmError::at(line, "Expecting '.upvalue local|upvalue <index>'.".to_string())),
+                };
+                let index = parse_number(line, tokens[2], "an upvalue index")?;
+                def.upvalues.push(UpvalueDesc { is_local, index });
+            },
+            (".constants", Section::Header) => section = Section::Constants,
+            (".code", Section::Header | Section::Constants) => section = Section::Code,
+            (".line", Section::Code) if tokens.len() == 2 => {
+                def.code.push((line, CodeItem::Line(parse_number(line, tokens[1], "a line number")?)));
+            },
+            (directive, _) if directive.starts_with('.') => {
+                return Err(AsmError::at(line, format!("Unexpected directive '{}'.", directive)));
+            },
+            (_, Section::Header) => {
+                return Err(AsmError::at(line, "Expecting '.constants' or '.code'.".to_string()));
+            },
+            (_, Section::Constants) => {
+                let constant = parse_constant(line, text, def.constants.len())?;
+                def.constants.push((line, constant));
+            },
+            (label, Section::Code) if tokens.len() == 1 && label.ends_with(':') => {
+                def.code.push((line, CodeItem::Label(text.trim_end_matches(':').to_string())));
+            },
+            (mnemonic, Section::Code) => {
+                let operands = tokens[1..].iter().map(|token| token.to_string()).collect();
+                def.code.push((line, CodeItem::Inst { mnemonic: mnemonic.to_string(), operands }));
+            },
+        }
+    }
+
+    if let Some(def) = current {
+        return Err(AsmError { message: format!("Missing .end of function {}.", def.id), line: None });
+    }
+    if defs.is_empty() {
+        return Err(AsmError { message: "No function to assemble.".to_string(), line: None });
+    }
+    Ok(defs)
+}
+
+/// Parse the constant `<index>: <value>`, which must have index `expected`.
+fn parse_constant(line: usize, text: &str, expected: usize) -> Result<ConstantDef, AsmError> {
+    let Some((idx, value)) = text.split_once(':') else {
+        return Err(AsmError::at(line, "Expecting '<index>: <constant>'.".to_string()));
+    };
+    if parse_number::<usize>(line, idx.trim(), "a constant index")? != expected {
+        return Err(AsmError::at(line, format!("Expecting constant {}, as constants are listed in order.", expected)));
+    }
+
+    let value = value.trim();
+    let constant = match value {
+        "nil" => ConstantDef::Nil,
+        "true" => ConstantDef::Bool(true),
+        "false" => ConstantDef::Bool(false),
+        _ if value.starts_with('"') => ConstantDef::Str(unescape_string(line, value)?),
+        _ => match value.split_whitespace().collect::<Vec<_>>()[..] {
+            ["function", id] => ConstantDef::Function(id.to_string()),
+            _ => ConstantDef::Number(parse_number(line, value, "a constant")?),
+        },
+    };
+    Ok(constant)
+}
+
+/// The contents of a string literal, see `debug::escape_string`.
+fn unescape_string(line: usize, literal: &str) -> Result<String, AsmError> {
+    let invalid = || AsmError::at(line, format!("Invalid string literal {}.", literal));
+    let inner = literal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')).ok_or_else(invalid)?;
+
+    let mut s = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => return Err(invalid()),
+            '\\' => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(invalid)?;
+                    let code = rest.strip_prefix('{').map(|code| &code[..end - 1]).ok_or_else(invalid)?;
+                    let ch = u32::from_str_radix(code, 16).ok().and_then(char::from_u32).ok_or_else(invalid)?;
+                    s.push(ch);
+                    chars = rest[end + 1..].chars();
+                },
+                _ => return Err(invalid()),
+            },
+            ch => s.push(ch),
+        }
+    }
+    Ok(s)
+}
+
+struct Builder<'a> {
+    defs: &'a [FunctionDef],
+    by_id: HashMap<String, usize>,
+    /// Nested functions already allocated, by index in `defs`.
+    built: HashMap<usize, ObjRef>,
+    /// Functions being built, to reject functions that contain themselves.
+    building: HashSet<usize>,
+    heap: &'a mut Heap,
+    globals: &'a mut Globals,
+}
+
+impl Builder<'_> {
+    fn build(&mut self, idx: usize) -> Result<Function, AsmError> {
+        let def = &self.defs[idx];
+        if self.building.len() >= NESTING_MAX {
+            return Err(AsmError::at(def.line, "Functions are nested too deeply.".to_string()));
+        }
+        self.building.insert(idx);
+
+        let mut function = Function::new(def.name.clone());
+        function.arity = def.arity;
+        function.upvalues = def.upvalues.clone();
+
+        for (line, constant) in &def.constants {
+            let value = self.constant(*line, constant)?;
+            let next = function.chunk.value_array.data.len();
+            match function.chunk.value_array.add_constant(value) {
+                Some(idx) if idx == next => {},
+                Some(idx) => return Err(AsmError::at(*line, format!("Duplicate of constant {}.", idx))),
+                None => return Err(AsmError::at(*line, "Too many constants in one chunk.".to_string())),
+            }
+        }
+
+        self.code(def, &mut function.chunk)?;
+
+        self.building.remove(&idx);
+        Ok(function)
+    }
+
+    fn constant(&mut self, line: usize, constant: &ConstantDef) -> Result<Value, AsmError> {
+        let value = match constant {
+            ConstantDef::Nil => Value::NIL,
+            ConstantDef::Bool(data) => Value::BOOL { data: *data },
+            ConstantDef::Number(data) => Value::DOUBLE { data: *data },
+            ConstantDef::Str(s) => Value::OBJ { data: self.heap.intern(s.clone()) },
+            ConstantDef::Function(id) => {
+                let idx = *self.by_id.get(id).ok_or_else(|| AsmError::at(line, format!("Unknown function {}.", id)))?;
+                if self.building.contains(&idx) {
+                    return Err(AsmError::at(line, format!("Function {} contains itself.", id)));
+                }
+
+                let r = match self.built.get(&idx) {
+                    Some(&r) => r,
+                    None => {
+                        let function = self.build(idx)?;
+                        let r = self.heap.alloc(Obj::Function { data: function });
+                        self.built.insert(idx, r);
+                        r
+                    },
+                };
+                Value::OBJ { data: r }
+            },
+        };
+        Ok(value)
+    }
+
+    /// Assemble the code of `def` into `chunk`, in two passes: the first one
+    /// places the labels, the second one encodes the jumps to them.
+    fn code(&mut self, def: &FunctionDef, chunk: &mut Chunk) -> Result<(), AsmError> {
+        let mut labels = HashMap::new();
+        let mut insts = Vec::new();
+        let mut sizing = Chunk::new();
+        let mut source_line = 0;
+
+        for (line, item) in &def.code {
+            match item {
+                CodeItem::Label(name) => {
+                    if labels.insert(name.as_str(), sizing.code.len()).is_some() {
+                        return Err(AsmError::at(*line, format!("Label {} is defined twice.", name)));
+                    }
+                },
+                CodeItem::Line(number) => source_line = *number,
+                CodeItem::Inst { mnemonic, operands } => {
+                    let (inst, target) = self.inst(*line, mnemonic, operands)?;
+                    insts.push((*line, sizing.code.len(), source_line, inst, target));
+                    sizing.write(inst, source_line);
+                },
+            }
+        }
+
+        for (line, offset, source_line, inst, target) in insts {
+            let inst = match target {
+                Some(target) => {
+                    let target = *labels.get(target).ok_or_else(|| AsmError::at(line, format!("Unknown label {}.", target)))?;
+                    jump(line, inst, offset + JUMP_LEN, target)?
+                },
+                None => inst,
+            };
+            chunk.write(inst, source_line);
+        }
+        Ok(())
+    }
+
+    /// Parse an instruction, along with the label it jumps to if any.
+    fn inst<'o>(&mut self, line: usize, mnemonic: &str, operands: &'o [String]) -> Result<(Inst, Option<&'o str>), AsmError> {
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                Err(AsmError::at(line, format!("{} takes {} operands, found {}.", mnemonic, count, operands.len())))
+            }
+        };
+        let one = || expect(1).map(|()| operands[0].as_str());
+        let byte = |token: &str| parse_number::<u8>(line, token, "a number up to 255").map(usize::from);
+        let index = |token: &str| parse_number::<u16>(line, token, "a constant index").map(usize::from);
+
+        let simple = match mnemonic {
+            "RETURN" => Some(Inst::RETURN),
+            "NEGATE" => Some(Inst::OP_NEGATE),
+            "ADD" => Some(Inst::OP_ADD),
+            "SUB" => Some(Inst::OP_SUB),
+            "MUL" => Some(Inst::OP_MUL),
+            "DIV" => Some(Inst::OP_DIV),
+            "NOT" => Some(Inst::OP_NOT),
+            "EQ" => Some(Inst::OP_EQ),
+            "GT" => Some(Inst::OP_GT),
+            "LT" => Some(Inst::OP_LT),
+            "GE" => Some(Inst::OP_GE),
+            "LE" => Some(Inst::OP_LE),
+            "PRINT" => Some(Inst::OP_PRINT),
+            "POP" => Some(Inst::OP_POP),
+            "CLOSE_UPVALUE" => Some(Inst::OP_CLOSE_UPVALUE),
+            "INHERIT" => Some(Inst::OP_INHERIT),
+            _ => None,
+        };
+        if let Some(inst) = simple {
+            expect(0)?;
+            return Ok((inst, None));
+        }
+
+        if let "JUMP" | "JUMP_IF_FALSE" | "LOOP" = mnemonic {
+            expect(1)?;
+            let inst = match mnemonic {
+                "JUMP" => Inst::OP_JUMP { offset: 0 },
+                "JUMP_IF_FALSE" => Inst::OP_JUMP_IF_FALSE { offset: 0 },
+                _ => Inst::OP_LOOP { offset: 0 },
+            };
+            return Ok((inst, Some(operands[0].as_str())));
+        }
+
+        if let "INVOKE" | "SUPER_INVOKE" = mnemonic {
+            expect(2)?;
+            let (name_idx, argc) = (index(&operands[0])?, byte(&operands[1])?);
+            let inst = match mnemonic {
+                "INVOKE" => Inst::OP_INVOKE { name_idx, argc },
+                _ => Inst::OP_SUPER_INVOKE { name_idx, argc },
+            };
+            return Ok((inst, None));
+        }
+
+        let inst = match mnemonic {
+            "CONSTANT" => Inst::CONSTANT { idx: index(one()?)? },
+            "CLOSURE" => Inst::OP_CLOSURE { idx: index(one()?)? },
+            "CLASS" => Inst::OP_CLASS { name_idx: index(one()?)? },
+            "GET_PROPERTY" => Inst::OP_GET_PROPERTY { name_idx: index(one()?)? },
+            "SET_PROPERTY" => Inst::OP_SET_PROPERTY { name_idx: index(one()?)? },
+            "METHOD" => Inst::OP_METHOD { name_idx: index(one()?)? },
+            "GET_SUPER" => Inst::OP_GET_SUPER { name_idx: index(one()?)? },
+            "GET_LOCAL" => Inst::OP_GET_LOCAL { slot: byte(one()?)? },
+            "SET_LOCAL" => Inst::OP_SET_LOCAL { slot: byte(one()?)? },
+            "GET_UPVALUE" => Inst::OP_GET_UPVALUE { idx: byte(one()?)? },
+            "SET_UPVALUE" => Inst::OP_SET_UPVALUE { idx: byte(one()?)? },
+            "CALL" => Inst::OP_CALL { argc: byte(one()?)? },
+            "DEFINE_GLOBAL" => Inst::OP_DEFINE_GLOBAL { slot: self.global(line, one()?)? },
+            "GET_GLOBAL" => Inst::OP_GET_GLOBAL { slot: self.global(line, one()?)? },
+            "SET_GLOBAL" => Inst::OP_SET_GLOBAL { slot: self.global(line, one()?)? },
+            _ => return Err(AsmError::at(line, format!("Unknown instruction {}.", mnemonic))),
+        };
+        Ok((inst, None))
+    }
+
+    fn global(&mut self, line: usize, name: &str) -> Result<usize, AsmError> {
+        let name = self.heap.intern(name.to_string());
+        let slot = self.globals.resolve(name);
+        if slot > SHORT_MAX {
+            return Err(AsmError::at(line, "Too many global variables.".to_string()));
+        }
+        Ok(slot)
+    }
+}
+
+/// Point the jump `inst`, followed by the instruction at `next`, at `target`.
+fn jump(line: usize, inst: Inst, next: usize, target: usize) -> Result<Inst, AsmError> {
+    let (offset, backwards) = match inst {
+        Inst::OP_LOOP { .. } => (next.checked_sub(target), true),
+        _ => (target.checked_sub(next), false),
+    };
+
+    let offset = match offset {
+        Some(offset) if offset <= SHORT_MAX => offset,
+        Some(_) => return Err(AsmError::at(line, "Jump too far.".to_string())),
+        None if backwards => return Err(AsmError::at(line, "LOOP can only jump backwards.".to_string())),
+        None => return Err(AsmError::at(line, format!("{} can only jump forwards.", inst.mnemonic()))),
+    };
+
+    Ok(match inst {
+        Inst::OP_JUMP { .. } => Inst::OP_JUMP { offset },
+        Inst::OP_JUMP_IF_FALSE { .. } => Inst::OP_JUMP_IF_FALSE { offset },
+        _ => Inst::OP_LOOP { offset },
+    })
+}
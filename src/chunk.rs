@@ -1,17 +1,114 @@
 use crate::value::ValueArray;
 
+/// A decoded instruction. Chunks store instructions encoded as bytes, see
+/// `OpCode` for the layout.
 #[derive(Debug)]
-#[derive(Clone)]
-pub enum KMethod {
-    Print = 0,
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Inst {
+    RETURN,
+    CONSTANT { idx: usize },
+    OP_NEGATE,
+    OP_ADD,
+    OP_SUB,
+    OP_MUL,
+    OP_DIV,
+    OP_NOT,
+    OP_EQ,
+    OP_GT,
+    OP_LT,
+    OP_GE,
+    OP_LE,
+    OP_PRINT,
+    OP_POP,
+    OP_DEFINE_GLOBAL { slot: usize },
+    OP_GET_GLOBAL { slot: usize },
+    OP_SET_GLOBAL { slot: usize },
+    OP_GET_LOCAL { slot: usize },
+    OP_SET_LOCAL { slot: usize },
+    /// Jumps are in bytes, relative to the instruction following the jump.
+    OP_JUMP { offset: usize },
+    OP_JUMP_IF_FALSE { offset: usize },
+    /// Jumps backwards by `offset` bytes, relative to the instruction following
+    /// the loop.
+    OP_LOOP { offset: usize },
+    OP_CALL { argc: usize },
+    /// Wraps the function constant at `idx` in a closure, capturing the
+    /// variables listed in its upvalue descriptors.
+    OP_CLOSURE { idx: usize },
+    OP_GET_UPVALUE { idx: usize },
+    OP_SET_UPVALUE { idx: usize },
+    OP_CLOSE_UPVALUE,
+    OP_CLASS { name_idx: usize },
+    OP_GET_PROPERTY { name_idx: usize },
+    OP_SET_PROPERTY { name_idx: usize },
+    OP_METHOD { name_idx: usize },
+    /// Call the method `name_idx` of the receiver below the `argc` arguments.
+    OP_INVOKE { name_idx: usize, argc: usize },
+    /// Copy the methods of the superclass below the subclass into the subclass.
+    OP_INHERIT,
+    OP_GET_SUPER { name_idx: usize },
+    /// Like `OP_INVOKE`, with the superclass to look the method up in on top
+    /// of the arguments.
+    OP_SUPER_INVOKE { name_idx: usize, argc: usize },
 }
 
+impl Inst {
+    /// Name of the instruction in assembly, see `asm`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Inst::RETURN => "RETURN",
+            Inst::CONSTANT { .. } => "CONSTANT",
+            Inst::OP_NEGATE => "NEGATE",
+            Inst::OP_ADD => "ADD",
+            Inst::OP_SUB => "SUB",
+            Inst::OP_MUL => "MUL",
+            Inst::OP_DIV => "DIV",
+            Inst::OP_NOT => "NOT",
+            Inst::OP_EQ => "EQ",
+            Inst::OP_GT => "GT",
+            Inst::OP_LT => "LT",
+            Inst::OP_GE => "GE",
+            Inst::OP_LE => "LE",
+            Inst::OP_PRINT => "PRINT",
+            Inst::OP_POP => "POP",
+            Inst::OP_DEFINE_GLOBAL { .. } => "DEFINE_GLOBAL",
+            Inst::OP_GET_GLOBAL { .. } => "GET_GLOBAL",
+            Inst::OP_SET_GLOBAL { .. } => "SET_GLOBAL",
+            Inst::OP_GET_LOCAL { .. } => "GET_LOCAL",
+            Inst::OP_SET_LOCAL { .. } => "SET_LOCAL",
+            Inst::OP_JUMP { .. } => "JUMP",
+            Inst::OP_JUMP_IF_FALSE { .. } => "JUMP_IF_FALSE",
+            Inst::OP_LOOP { .. } => "LOOP",
+            Inst::OP_CALL { .. } => "CALL",
+            Inst::OP_CLOSURE { .. } => "CLOSURE",
+            Inst::OP_GET_UPVALUE { .. } => "GET_UPVALUE",
+            Inst::OP_SET_UPVALUE { .. } => "SET_UPVALUE",
+            Inst::OP_CLOSE_UPVALUE => "CLOSE_UPVALUE",
+            Inst::OP_CLASS { .. } => "CLASS",
+            Inst::OP_GET_PROPERTY { .. } => "GET_PROPERTY",
+            Inst::OP_SET_PROPERTY { .. } => "SET_PROPERTY",
+            Inst::OP_METHOD { .. } => "METHOD",
+            Inst::OP_INVOKE { .. } => "INVOKE",
+            Inst::OP_INHERIT => "INHERIT",
+            Inst::OP_GET_SUPER { .. } => "GET_SUPER",
+            Inst::OP_SUPER_INVOKE { .. } => "SUPER_INVOKE",
+        }
+    }
+}
 
+/// One-byte opcodes of encoded instructions. Operands follow the opcode in
+/// little-endian order and take one byte each, except jump offsets, which
+/// take two. The `_LONG` variants take two-byte constant indexes or global
+/// slots, for operands above 255.
 #[derive(Debug)]
-#[derive(Clone)]
-pub enum Inst {
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum OpCode {
     RETURN,
-    CONSTANT { idx: usize },
+    CONSTANT,
+    CONSTANT_LONG,
     OP_NEGATE,
     OP_ADD,
     OP_SUB,
@@ -21,31 +118,290 @@ pub enum Inst {
     OP_EQ,
     OP_GT,
     OP_LT,
-    OP_KCALL { tp: KMethod },
+    OP_GE,
+    OP_LE,
+    OP_PRINT,
     OP_POP,
-    OP_DEFINE_GLOBAL { name_idx: usize },
-    OP_GET_GLOBAL { name_idx: usize },
+    OP_DEFINE_GLOBAL,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_GET_GLOBAL,
+    OP_GET_GLOBAL_LONG,
+    OP_SET_GLOBAL,
+    OP_SET_GLOBAL_LONG,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_CLOSURE,
+    OP_CLOSURE_LONG,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_CLOSE_UPVALUE,
+    OP_CLASS,
+    OP_CLASS_LONG,
+    OP_GET_PROPERTY,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY,
+    OP_SET_PROPERTY_LONG,
+    OP_METHOD,
+    OP_METHOD_LONG,
+    OP_INVOKE,
+    OP_INVOKE_LONG,
+    OP_INHERIT,
+    OP_GET_SUPER,
+    OP_GET_SUPER_LONG,
+    OP_SUPER_INVOKE,
+    OP_SUPER_INVOKE_LONG,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 48] = [
+        OpCode::RETURN, OpCode::CONSTANT, OpCode::CONSTANT_LONG,
+        OpCode::OP_NEGATE, OpCode::OP_ADD, OpCode::OP_SUB, OpCode::OP_MUL, OpCode::OP_DIV,
+        OpCode::OP_NOT, OpCode::OP_EQ, OpCode::OP_GT, OpCode::OP_LT, OpCode::OP_GE, OpCode::OP_LE,
+        OpCode::OP_PRINT, OpCode::OP_POP,
+        OpCode::OP_DEFINE_GLOBAL, OpCode::OP_DEFINE_GLOBAL_LONG,
+        OpCode::OP_GET_GLOBAL, OpCode::OP_GET_GLOBAL_LONG,
+        OpCode::OP_SET_GLOBAL, OpCode::OP_SET_GLOBAL_LONG,
+        OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL,
+        OpCode::OP_JUMP, OpCode::OP_JUMP_IF_FALSE, OpCode::OP_LOOP,
+        OpCode::OP_CALL, OpCode::OP_CLOSURE, OpCode::OP_CLOSURE_LONG,
+        OpCode::OP_GET_UPVALUE, OpCode::OP_SET_UPVALUE, OpCode::OP_CLOSE_UPVALUE,
+        OpCode::OP_CLASS, OpCode::OP_CLASS_LONG,
+        OpCode::OP_GET_PROPERTY, OpCode::OP_GET_PROPERTY_LONG,
+        OpCode::OP_SET_PROPERTY, OpCode::OP_SET_PROPERTY_LONG,
+        OpCode::OP_METHOD, OpCode::OP_METHOD_LONG,
+        OpCode::OP_INVOKE, OpCode::OP_INVOKE_LONG,
+        OpCode::OP_INHERIT,
+        OpCode::OP_GET_SUPER, OpCode::OP_GET_SUPER_LONG,
+        OpCode::OP_SUPER_INVOKE, OpCode::OP_SUPER_INVOKE_LONG,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+}
+
+/// Largest operand that fits the one-byte encoding.
+const BYTE_MAX: usize = u8::MAX as usize;
+/// Largest operand that fits the two-byte encoding, which bounds jump
+/// offsets, constant indexes and global slots.
+pub const SHORT_MAX: usize = u16::MAX as usize;
+/// Length in bytes of jump and loop instructions.
+pub const JUMP_LEN: usize = 3;
+
+/// Instructions from `offset` on were compiled from source line `line`.
+#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LineRun {
+    pub offset: usize,
+    pub line: usize,
 }
 
 #[derive(Debug)]
 pub struct Chunk {
-    pub data: Vec<Inst>,
+    /// Encoded instructions, see `OpCode`.
+    pub code: Vec<u8>,
     pub value_array: ValueArray,
-    pub lines: Vec<usize>,
+    /// Source lines, run-length encoded: one run per stretch of code compiled
+    /// from the same line, in increasing offset order.
+    pub lines: Vec<LineRun>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
-    pub fn write(&mut self, op: Inst, line: usize) {
-        self.data.push(op);
-        self.lines.push(line);
+    /// Encode `inst` at the end of the chunk. Operands must fit the encoding,
+    /// which the compiler ensures by bounding them beforehand.
+    pub fn write(&mut self, inst: Inst, line: usize) {
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun { offset: self.code.len(), line });
+        }
+
+        match inst {
+            Inst::RETURN => self.write_op(OpCode::RETURN),
+            Inst::CONSTANT { idx } => self.write_index(OpCode::CONSTANT, OpCode::CONSTANT_LONG, idx),
+            Inst::OP_NEGATE => self.write_op(OpCode::OP_NEGATE),
+            Inst::OP_ADD => self.write_op(OpCode::OP_ADD),
+            Inst::OP_SUB => self.write_op(OpCode::OP_SUB),
+            Inst::OP_MUL => self.write_op(OpCode::OP_MUL),
+            Inst::OP_DIV => self.write_op(OpCode::OP_DIV),
+            Inst::OP_NOT => self.write_op(OpCode::OP_NOT),
+            Inst::OP_EQ => self.write_op(OpCode::OP_EQ),
+            Inst::OP_GT => self.write_op(OpCode::OP_GT),
+            Inst::OP_LT => self.write_op(OpCode::OP_LT),
+            Inst::OP_GE => self.write_op(OpCode::OP_GE),
+            Inst::OP_LE => self.write_op(OpCode::OP_LE),
+            Inst::OP_PRINT => self.write_op(OpCode::OP_PRINT),
+            Inst::OP_POP => self.write_op(OpCode::OP_POP),
+            Inst::OP_DEFINE_GLOBAL { slot } => {
+                self.write_index(OpCode::OP_DEFINE_GLOBAL, OpCode::OP_DEFINE_GLOBAL_LONG, slot)
+            },
+            Inst::OP_GET_GLOBAL { slot } => {
+                self.write_index(OpCode::OP_GET_GLOBAL, OpCode::OP_GET_GLOBAL_LONG, slot)
+            },
+            Inst::OP_SET_GLOBAL { slot } => {
+                self.write_index(OpCode::OP_SET_GLOBAL, OpCode::OP_SET_GLOBAL_LONG, slot)
+            },
+            Inst::OP_GET_LOCAL { slot } => self.write_with_byte(OpCode::OP_GET_LOCAL, slot),
+            Inst::OP_SET_LOCAL { slot } => self.write_with_byte(OpCode::OP_SET_LOCAL, slot),
+            Inst::OP_JUMP { offset } => self.write_with_short(OpCode::OP_JUMP, offset),
+            Inst::OP_JUMP_IF_FALSE { offset } => self.write_with_short(OpCode::OP_JUMP_IF_FALSE, offset),
+            Inst::OP_LOOP { offset } => self.write_with_short(OpCode::OP_LOOP, offset),
+            Inst::OP_CALL { argc } => self.write_with_byte(OpCode::OP_CALL, argc),
+            Inst::OP_CLOSURE { idx } => self.write_index(OpCode::OP_CLOSURE, OpCode::OP_CLOSURE_LONG, idx),
+            Inst::OP_GET_UPVALUE { idx } => self.write_with_byte(OpCode::OP_GET_UPVALUE, idx),
+            Inst::OP_SET_UPVALUE { idx } => self.write_with_byte(OpCode::OP_SET_UPVALUE, idx),
+            Inst::OP_CLOSE_UPVALUE => self.write_op(OpCode::OP_CLOSE_UPVALUE),
+            Inst::OP_CLASS { name_idx } => self.write_index(OpCode::OP_CLASS, OpCode::OP_CLASS_LONG, name_idx),
+            Inst::OP_GET_PROPERTY { name_idx } => {
+                self.write_index(OpCode::OP_GET_PROPERTY, OpCode::OP_GET_PROPERTY_LONG, name_idx)
+            },
+            Inst::OP_SET_PROPERTY { name_idx } => {
+                self.write_index(OpCode::OP_SET_PROPERTY, OpCode::OP_SET_PROPERTY_LONG, name_idx)
+            },
+            Inst::OP_METHOD { name_idx } => self.write_index(OpCode::OP_METHOD, OpCode::OP_METHOD_LONG, name_idx),
+            Inst::OP_INVOKE { name_idx, argc } => {
+                self.write_index(OpCode::OP_INVOKE, OpCode::OP_INVOKE_LONG, name_idx);
+                self.write_byte(argc);
+            },
+            Inst::OP_INHERIT => self.write_op(OpCode::OP_INHERIT),
+            Inst::OP_GET_SUPER { name_idx } => {
+                self.write_index(OpCode::OP_GET_SUPER, OpCode::OP_GET_SUPER_LONG, name_idx)
+            },
+            Inst::OP_SUPER_INVOKE { name_idx, argc } => {
+                self.write_index(OpCode::OP_SUPER_INVOKE, OpCode::OP_SUPER_INVOKE_LONG, name_idx);
+                self.write_byte(argc);
+            },
+        }
+    }
+
+    fn write_op(&mut self, op: OpCode) {
+        self.code.push(op as u8);
+    }
+
+    fn write_byte(&mut self, operand: usize) {
+        let byte = u8::try_from(operand).expect("Operand does not fit in a byte");
+        self.code.push(byte);
+    }
+
+    fn write_short(&mut self, operand: usize) {
+        let short = u16::try_from(operand).expect("Operand does not fit in two bytes");
+        self.code.extend_from_slice(&short.to_le_bytes());
+    }
+
+    fn write_with_byte(&mut self, op: OpCode, operand: usize) {
+        self.write_op(op);
+        self.write_byte(operand);
+    }
+
+    fn write_with_short(&mut self, op: OpCode, operand: usize) {
+        self.write_op(op);
+        self.write_short(operand);
+    }
+
+    /// Write a constant index or global slot, in the `_LONG` variant of the
+    /// instruction when it does not fit in a byte.
+    fn write_index(&mut self, op: OpCode, long: OpCode, idx: usize) {
+        if idx <= BYTE_MAX {
+            self.write_with_byte(op, idx);
+        } else {
+            self.write_with_short(long, idx);
+        }
+    }
+
+    /// Decode the instruction at `offset`, along with its length in bytes.
+    /// Returns `None` if the bytes there are not a complete instruction.
+    pub fn decode(&self, offset: usize) -> Option<(Inst, usize)> {
+        let byte = |at: usize| self.code.get(offset + at).map(|&b| b as usize);
+        let short = |at: usize| Some(byte(at)? | byte(at + 1)? << 8);
+
+        let decoded = match OpCode::from_byte(*self.code.get(offset)?)? {
+            OpCode::RETURN => (Inst::RETURN, 1),
+            OpCode::CONSTANT => (Inst::CONSTANT { idx: byte(1)? }, 2),
+            OpCode::CONSTANT_LONG => (Inst::CONSTANT { idx: short(1)? }, 3),
+            OpCode::OP_NEGATE => (Inst::OP_NEGATE, 1),
+            OpCode::OP_ADD => (Inst::OP_ADD, 1),
+            OpCode::OP_SUB => (Inst::OP_SUB, 1),
+            OpCode::OP_MUL => (Inst::OP_MUL, 1),
+            OpCode::OP_DIV => (Inst::OP_DIV, 1),
+            OpCode::OP_NOT => (Inst::OP_NOT, 1),
+            OpCode::OP_EQ => (Inst::OP_EQ, 1),
+            OpCode::OP_GT => (Inst::OP_GT, 1),
+            OpCode::OP_LT => (Inst::OP_LT, 1),
+            OpCode::OP_GE => (Inst::OP_GE, 1),
+            OpCode::OP_LE => (Inst::OP_LE, 1),
+            OpCode::OP_PRINT => (Inst::OP_PRINT, 1),
+            OpCode::OP_POP => (Inst::OP_POP, 1),
+            OpCode::OP_DEFINE_GLOBAL => (Inst::OP_DEFINE_GLOBAL { slot: byte(1)? }, 2),
+            OpCode::OP_DEFINE_GLOBAL_LONG => (Inst::OP_DEFINE_GLOBAL { slot: short(1)? }, 3),
+            OpCode::OP_GET_GLOBAL => (Inst::OP_GET_GLOBAL { slot: byte(1)? }, 2),
+            OpCode::OP_GET_GLOBAL_LONG => (Inst::OP_GET_GLOBAL { slot: short(1)? }, 3),
+            OpCode::OP_SET_GLOBAL => (Inst::OP_SET_GLOBAL { slot: byte(1)? }, 2),
+            OpCode::OP_SET_GLOBAL_LONG => (Inst::OP_SET_GLOBAL { slot: short(1)? }, 3),
+            OpCode::OP_GET_LOCAL => (Inst::OP_GET_LOCAL { slot: byte(1)? }, 2),
+            OpCode::OP_SET_LOCAL => (Inst::OP_SET_LOCAL { slot: byte(1)? }, 2),
+            OpCode::OP_JUMP => (Inst::OP_JUMP { offset: short(1)? }, 3),
+            OpCode::OP_JUMP_IF_FALSE => (Inst::OP_JUMP_IF_FALSE { offset: short(1)? }, 3),
+            OpCode::OP_LOOP => (Inst::OP_LOOP { offset: short(1)? }, 3),
+            OpCode::OP_CALL => (Inst::OP_CALL { argc: byte(1)? }, 2),
+            OpCode::OP_CLOSURE => (Inst::OP_CLOSURE { idx: byte(1)? }, 2),
+            OpCode::OP_CLOSURE_LONG => (Inst::OP_CLOSURE { idx: short(1)? }, 3),
+            OpCode::OP_GET_UPVALUE => (Inst::OP_GET_UPVALUE { idx: byte(1)? }, 2),
+            OpCode::OP_SET_UPVALUE => (Inst::OP_SET_UPVALUE { idx: byte(1)? }, 2),
+            OpCode::OP_CLOSE_UPVALUE => (Inst::OP_CLOSE_UPVALUE, 1),
+            OpCode::OP_CLASS => (Inst::OP_CLASS { name_idx: byte(1)? }, 2),
+            OpCode::OP_CLASS_LONG => (Inst::OP_CLASS { name_idx: short(1)? }, 3),
+            OpCode::OP_GET_PROPERTY => (Inst::OP_GET_PROPERTY { name_idx: byte(1)? }, 2),
+            OpCode::OP_GET_PROPERTY_LONG => (Inst::OP_GET_PROPERTY { name_idx: short(1)? }, 3),
+            OpCode::OP_SET_PROPERTY => (Inst::OP_SET_PROPERTY { name_idx: byte(1)? }, 2),
+            OpCode::OP_SET_PROPERTY_LONG => (Inst::OP_SET_PROPERTY { name_idx: short(1)? }, 3),
+            OpCode::OP_METHOD => (Inst::OP_METHOD { name_idx: byte(1)? }, 2),
+            OpCode::OP_METHOD_LONG => (Inst::OP_METHOD { name_idx: short(1)? }, 3),
+            OpCode::OP_INVOKE => (Inst::OP_INVOKE { name_idx: byte(1)?, argc: byte(2)? }, 3),
+            OpCode::OP_INVOKE_LONG => (Inst::OP_INVOKE { name_idx: short(1)?, argc: byte(3)? }, 4),
+            OpCode::OP_INHERIT => (Inst::OP_INHERIT, 1),
+            OpCode::OP_GET_SUPER => (Inst::OP_GET_SUPER { name_idx: byte(1)? }, 2),
+            OpCode::OP_GET_SUPER_LONG => (Inst::OP_GET_SUPER { name_idx: short(1)? }, 3),
+            OpCode::OP_SUPER_INVOKE => (Inst::OP_SUPER_INVOKE { name_idx: byte(1)?, argc: byte(2)? }, 3),
+            OpCode::OP_SUPER_INVOKE_LONG => {
+                (Inst::OP_SUPER_INVOKE { name_idx: short(1)?, argc: byte(3)? }, 4)
+            },
+        };
+        Some(decoded)
+    }
+
+    /// Source line of the instruction covering `offset`.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|run| run.offset <= offset);
+        run.checked_sub(1).map_or(0, |run| self.lines[run].line)
+    }
+
+    /// Point the forward jump at offset `at` to the next instruction to be
+    /// written.
+    pub fn patch_jump(&mut self, at: usize) {
+        let target = self.code.len() - at - JUMP_LEN;
+
+        match self.decode(at) {
+            Some((Inst::OP_JUMP { .. } | Inst::OP_JUMP_IF_FALSE { .. }, _)) => {
+                let short = u16::try_from(target).expect("Jump offset does not fit in two bytes");
+                self.code[at + 1..at + JUMP_LEN].copy_from_slice(&short.to_le_bytes());
+            },
+            inst => panic!("Patching a non-jump instruction: {:?}", inst),
+        }
     }
 
     pub fn new() -> Chunk {
         Chunk {
-            data: Vec::new(),
+            code: Vec::new(),
             value_array: ValueArray::new(),
             lines: Vec::new()
         }
     }
 }
-
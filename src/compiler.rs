@@ -2,59 +2,177 @@ use crate::scanner::{TokenType, Token, ScannerState, next_token};
 use crate::parser::ParserState;
 use crate::parser;
 use crate::chunk::{Inst, Chunk};
+use crate::obj::{Function, UpvalueDesc};
+use crate::value::Value;
+use crate::heap::Heap;
+use crate::globals::Globals;
 
-pub struct Compiler {
+/// Maximum number of locals that may be live at the same time.
+pub const LOCALS_MAX: usize = 256;
+/// Maximum number of variables a single function may capture.
+pub const UPVALUES_MAX: usize = 256;
+
+#[derive(Debug)]
+pub struct Local {
+    pub name: String,
+    /// Scope depth of the declaring block, or `None` while the initializer of
+    /// the variable is still being compiled.
+    pub depth: Option<usize>,
+    /// Whether a nested function captures this local, in which case it has
+    /// to be moved off the stack when it goes out of scope.
+    pub is_captured: bool,
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FunctionType {
+    Script,
+    Function,
+    Method,
+    /// The `init` method of a class, which always returns the instance.
+    Initializer,
+}
+
+/// Compilation state of a class declaration, used to validate `this` and
+/// `super`.
+#[derive(Debug)]
+pub struct ClassState {
+    pub has_superclass: bool,
+}
+
+/// Compilation state of a single function. Nested function declarations push
+/// a new state on top of the one of the enclosing function.
+pub struct FunctionState {
+    pub function: Function,
+    pub fn_type: FunctionType,
+    /// Locals in declaration order. The index of a local is its stack slot,
+    /// relative to the base of the call frame.
+    pub locals: Vec<Local>,
+    pub scope_depth: usize,
+}
+
+impl FunctionState {
+    pub fn new(fn_type: FunctionType, name: Option<String>) -> FunctionState {
+        // Slot zero holds the function being called, or the receiver in
+        // methods, where it is reachable as `this`.
+        let slot_zero = match fn_type {
+            FunctionType::Method | FunctionType::Initializer => "this".to_string(),
+            FunctionType::Script | FunctionType::Function => String::new(),
+        };
+        let locals = vec![Local { name: slot_zero, depth: Some(0), is_captured: false }];
+
+        FunctionState {
+            function: Function::new(name),
+            fn_type,
+            locals,
+            scope_depth: 0,
+        }
+    }
+
+    /// Index of the upvalue capturing `desc`, adding it if it is new. Returns
+    /// `None` when the function has too many upvalues.
+    pub fn add_upvalue(&mut self, desc: UpvalueDesc) -> Option<usize> {
+        let upvalues = &mut self.function.upvalues;
+
+        if let Some(idx) = upvalues.iter().position(|upvalue| *upvalue == desc) {
+            return Some(idx);
+        }
+
+        if upvalues.len() >= UPVALUES_MAX {
+            return None;
+        }
+
+        upvalues.push(desc);
+        Some(upvalues.len() - 1)
+    }
+}
+
+pub struct Compiler<'a> {
     pub source: String,
     pub scanner: ScannerState,
     pub parser: ParserState,
-    pub current_chunk: Chunk,
+    pub states: Vec<FunctionState>,
+    /// Enclosing class declarations, innermost last.
+    pub classes: Vec<ClassState>,
+    /// Heap of the VM that will run the code, which owns the string and
+    /// function constants. It does not collect during compilation.
+    pub heap: &'a mut Heap,
+    /// Globals of the VM, where global names are resolved to slots.
+    pub globals: &'a mut Globals,
 }
 
-impl Compiler {
-    pub fn new(source: String) -> Compiler {
+impl<'a> Compiler<'a> {
+    pub fn new(source: String, heap: &'a mut Heap, globals: &'a mut Globals) -> Compiler<'a> {
         Compiler {
             source,
             scanner: ScannerState::new(),
             parser: ParserState::new(),
-            current_chunk: Chunk::new(),
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: Vec::new(),
+            heap,
+            globals,
         }
     }
 
-    pub fn compile(&mut self) -> bool {
-        // let mut line = 1 << 20;
-        // loop {
-        //     let tok = next_token(self);
-
-        //     match tok.tp {
-        //         TokenType::EOF => break,
-        //         _ => {},
-        //     }
-
-        //     if tok.line != line {
-        //         println!("{:04} {}", tok.line, tok.show());
-        //         line = tok.line;
-        //     } else {
-        //         println!("   | {}", tok.show());
-        //     }
-        // }
-
+    /// Compile the whole source into the top-level script function. Returns
+    /// `None` if any compile error has been reported.
+    pub fn compile(&mut self) -> Option<Function> {
         parser::advance(self);
-        // parser::parse_expression(self);
-        // parser::consume(self, TokenType::EOF, "Expecting end of expression.");
         while !parser::try_consume(self, TokenType::EOF) {
             parser::parse_decl(self);
         }
 
-        self.emit_inst(Inst::RETURN);
+        let function = self.end_function();
 
-        return !self.parser.had_error;
+        if self.parser.had_error {
+            None
+        } else {
+            Some(function)
+        }
     }
 
     pub fn next_token(&mut self) -> Token {
         next_token(self)
     }
 
+    pub fn current(&self) -> &FunctionState {
+        self.states.last().expect("Compiling outside of any function")
+    }
+
+    pub fn current_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("Compiling outside of any function")
+    }
+
+    pub fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current_mut().function.chunk
+    }
+
+    pub fn begin_function(&mut self, fn_type: FunctionType, name: String) {
+        self.states.push(FunctionState::new(fn_type, Some(name)));
+    }
+
+    /// Finish the innermost function, returning `nil` if control falls off
+    /// its end.
+    pub fn end_function(&mut self) -> Function {
+        self.emit_return();
+        let state = self.states.pop().expect("Compiling outside of any function");
+        state.function
+    }
+
+    /// Emit an implicit return: `nil` for functions, and the instance for
+    /// initializers.
+    pub fn emit_return(&mut self) {
+        if self.current().fn_type == FunctionType::Initializer {
+            self.emit_inst(Inst::OP_GET_LOCAL { slot: 0 });
+        } else {
+            let idx = parser::make_constant(self, Value::NIL);
+            self.emit_inst(Inst::CONSTANT { idx });
+        }
+        self.emit_inst(Inst::RETURN);
+    }
+
     pub fn emit_inst(&mut self, inst: Inst) {
-        self.current_chunk.write(inst, self.parser.previous.line as usize);
+        let line = self.parser.previous.line as usize;
+        self.current_chunk().write(inst, line);
     }
 }
@@ -1,64 +1,216 @@
-use crate::chunk::{Inst, Chunk};
+use crate::chunk::{Inst, Chunk, JUMP_LEN};
 use crate::value::Value;
-use crate::obj::Obj;
-
-pub fn display_inst(inst: &Inst, chunk: &Chunk) {
-    match inst {
-        Inst::RETURN => println!("RETURN"),
-        Inst::CONSTANT { idx } => {
-            let constant = &chunk.value_array.data[*idx];
-            println!("CONSTANT {} ({})", idx, show_value(constant));
+use crate::obj::{Obj, Function};
+use crate::heap::{Heap, ObjRef};
+use crate::globals::Globals;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Column at which comments start in disassembled code.
+const COMMENT_COLUMN: usize = 28;
+
+/// Label of the instruction at `offset`, as written by the disassembler.
+pub fn label(offset: usize) -> String {
+    format!("L{}", offset)
+}
+
+/// Write `inst`, found at `offset` in `chunk`, in assembly syntax. Operands
+/// that index the constants are followed by a comment showing the constant.
+pub fn write_inst(out: &mut impl fmt::Write, heap: &Heap, globals: &Globals, inst: &Inst, offset: usize, chunk: &Chunk)
+    -> fmt::Result
+{
+    let next = offset + JUMP_LEN;
+    // Strings are escaped, to keep the comment on one line.
+    let constant = |idx: usize| match chunk.value_array.data.get(idx) {
+        Some(value) => heap.as_string(value).map_or_else(|| show_value(heap, value), escape_string),
+        None => "?".to_string(),
+    };
+
+    let (text, comment) = match *inst {
+        Inst::CONSTANT { idx } | Inst::OP_CLOSURE { idx } => (format!("{} {}", inst.mnemonic(), idx), Some(constant(idx))),
+        Inst::OP_DEFINE_GLOBAL { slot } | Inst::OP_GET_GLOBAL { slot } | Inst::OP_SET_GLOBAL { slot } => {
+            (format!("{} {}", inst.mnemonic(), show_global(heap, globals, slot)), None)
         },
-        Inst::OP_NEGATE => println!("OP_NEGATE"),
-        Inst::OP_ADD => println!("OP_ADD"),
-        Inst::OP_SUB => println!("OP_SUB"),
-        Inst::OP_DIV => println!("OP_DIV"),
-        Inst::OP_MUL => println!("OP_MUL"),
-        Inst::OP_NOT => println!("OP_NOT"),
-        Inst::OP_EQ => println!("OP_EQ"),
-        Inst::OP_GT => println!("OP_GT"),
-        Inst::OP_LT => println!("OP_LT"),
-        Inst::OP_KCALL { tp } => println!("OP_KCALL {}", tp.clone() as u32),
-        Inst::OP_POP => println!("OP_POP"),
-        Inst::OP_DEFINE_GLOBAL { name_idx } => {
-            let var_name = &chunk.value_array.data[*name_idx];
-            println!("DEFINE_GLOBAL {} ({})", name_idx, show_value(var_name));
+        Inst::OP_GET_LOCAL { slot } | Inst::OP_SET_LOCAL { slot } => (format!("{} {}", inst.mnemonic(), slot), None),
+        Inst::OP_GET_UPVALUE { idx } | Inst::OP_SET_UPVALUE { idx } => (format!("{} {}", inst.mnemonic(), idx), None),
+        Inst::OP_CALL { argc } => (format!("{} {}", inst.mnemonic(), argc), None),
+        Inst::OP_JUMP { offset } | Inst::OP_JUMP_IF_FALSE { offset } => {
+            (format!("{} {}", inst.mnemonic(), label(next + offset)), None)
         },
-        Inst::OP_GET_GLOBAL { name_idx } => {
-            let var_name = &chunk.value_array.data[*name_idx];
-            println!("GET_GLOBAL {} ({})", name_idx, show_value(var_name));
+        Inst::OP_LOOP { offset } => (format!("{} {}", inst.mnemonic(), label(next.saturating_sub(offset))), None),
+        Inst::OP_CLASS { name_idx }
+        | Inst::OP_GET_PROPERTY { name_idx }
+        | Inst::OP_SET_PROPERTY { name_idx }
+        | Inst::OP_METHOD { name_idx }
+        | Inst::OP_GET_SUPER { name_idx } => (format!("{} {}", inst.mnemonic(), name_idx), Some(constant(name_idx))),
+        Inst::OP_INVOKE { name_idx, argc } | Inst::OP_SUPER_INVOKE { name_idx, argc } => {
+            (format!("{} {} {}", inst.mnemonic(), name_idx, argc), Some(constant(name_idx)))
         },
+        _ => (inst.mnemonic().to_string(), None),
+    };
+
+    match comment {
+        Some(comment) => write!(out, "{:width$}; {}", text, comment, width = COMMENT_COLUMN - 4),
+        None => write!(out, "{}", text),
     }
 }
 
-pub fn show_value(value: &Value) -> String {
+/// Print the instruction about to be executed, for tracing.
+pub fn display_inst(heap: &Heap, globals: &Globals, inst: &Inst, offset: usize, chunk: &Chunk) {
+    let mut text = String::new();
+    write_inst(&mut text, heap, globals, inst, offset, chunk).expect("Writing to a string cannot fail");
+    println!("{:04} {}", offset, text);
+}
+
+/// The name of the global in `slot`.
+pub fn show_global(heap: &Heap, globals: &Globals, slot: usize) -> String {
+    globals.name(slot).and_then(|name| heap.get(name).as_str()).unwrap_or("?").to_string()
+}
+
+pub fn show_value(heap: &Heap, value: &Value) -> String {
     match value {
         Value::DOUBLE { data } => format!("{}", data),
         Value::BOOL { data } => format!("{}", data),
         Value::NIL => "nil".into(),
-        Value::OBJ { data } => show_obj(data),
+        Value::OBJ { data } => show_obj(heap, heap.get(*data)),
         Value::EMPTY => "EMPTY".to_string()
     }
 }
 
-pub fn show_obj(obj: &Obj) -> String {
+pub fn show_obj(heap: &Heap, obj: &Obj) -> String {
     match obj {
-        Obj::Str { data } => format!("'{}'", data),
+        Obj::Str { data, .. } => format!("'{}'", data),
+        Obj::Function { data } => show_function(data),
+        Obj::Closure { data } => show_obj(heap, heap.get(data.function)),
+        Obj::Upvalue { data: _ } => "upvalue".to_string(),
+        Obj::Class { data } => data.name.clone(),
+        Obj::Instance { data } => format!("{} instance", show_obj(heap, heap.get(data.class))),
+        Obj::BoundMethod { data } => show_obj(heap, heap.get(data.method)),
+        Obj::Native { data } => format!("<native fn {}>", data.name),
+    }
+}
+
+pub fn show_function(function: &Function) -> String {
+    match &function.name {
+        Some(name) => format!("<fn {}>", name),
+        None => "<script>".to_string(),
     }
 }
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("===== {} =====", name);
-    for idx in 0..chunk.data.len() {
-        let inst = &chunk.data[idx];
-        let lineno = &chunk.lines[idx];
-        if idx == 0 || *lineno != chunk.lines[idx - 1] {
-            print!("{:04} ", lineno);
-        } else {
-            print!("   | ");
+/// A string literal of the assembly syntax, with quotes, backslashes and
+/// control characters escaped.
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => escaped.push(ch),
         }
-        display_inst(inst, chunk);
     }
+    escaped.push('"');
+    escaped
 }
 
+/// Write `function`, then the functions nested in its constants, in the
+/// assembly syntax read by `asm::assemble`. The script is `f0` and nested
+/// functions are numbered in the order they are found.
+pub fn disassemble(out: &mut impl fmt::Write, heap: &Heap, globals: &Globals, function: &Function) -> fmt::Result {
+    let mut ids: HashMap<ObjRef, usize> = HashMap::new();
+    let mut pending: Vec<&Function> = vec![function];
+    let mut next = 0;
 
+    while next < pending.len() {
+        if next > 0 {
+            writeln!(out)?;
+        }
+        let function = pending[next];
+        write_function(out, heap, globals, function, next, |r| {
+            let count = ids.len();
+            *ids.entry(r).or_insert_with(|| {
+                if let Some(nested) = heap.get(r).as_function() {
+                    pending.push(nested);
+                }
+                count + 1
+            })
+        })?;
+        next += 1;
+    }
+    Ok(())
+}
+
+fn write_function(
+    out: &mut impl fmt::Write,
+    heap: &Heap,
+    globals: &Globals,
+    function: &Function,
+    id: usize,
+    mut function_id: impl FnMut(ObjRef) -> usize,
+) -> fmt::Result {
+    writeln!(out, ".function f{} {} {}", id, function.name.as_deref().unwrap_or("-"), function.arity)?;
+    for upvalue in &function.upvalues {
+        let kind = if upvalue.is_local { "local" } else { "upvalue" };
+        writeln!(out, ".upvalue {} {}", kind, upvalue.index)?;
+    }
+
+    let chunk = &function.chunk;
+    writeln!(out, ".constants")?;
+    for (idx, constant) in chunk.value_array.data.iter().enumerate() {
+        write!(out, "    {}: ", idx)?;
+        match constant {
+            Value::DOUBLE { data } => writeln!(out, "{:?}", data)?,
+            Value::OBJ { data } => match heap.get(*data) {
+                Obj::Str { data, .. } => writeln!(out, "{}", escape_string(data))?,
+                Obj::Function { .. } => writeln!(out, "function f{}", function_id(*data))?,
+                // Not a valid constant, which the assembler reports.
+                obj => writeln!(out, "<{}>", show_obj(heap, obj))?,
+            },
+            constant => writeln!(out, "{}", show_value(heap, constant))?,
+        }
+    }
+
+    // Decode everything first, to label the targets of jumps.
+    let mut insts = Vec::new();
+    let mut offset = 0;
+    while let Some((inst, len)) = chunk.decode(offset) {
+        insts.push((offset, inst));
+        offset += len;
+    }
+    let targets: HashSet<usize> = insts.iter().filter_map(|&(offset, inst)| match inst {
+        Inst::OP_JUMP { offset: jump } | Inst::OP_JUMP_IF_FALSE { offset: jump } => Some(offset + JUMP_LEN + jump),
+        Inst::OP_LOOP { offset: jump } => Some((offset + JUMP_LEN).saturating_sub(jump)),
+        _ => None,
+    }).collect();
+
+    writeln!(out, ".code")?;
+    let mut line = None;
+    for &(offset, inst) in &insts {
+        if targets.contains(&offset) {
+            writeln!(out, "{}:", label(offset))?;
+        }
+        let inst_line = chunk.line_at(offset);
+        if line != Some(inst_line) {
+            writeln!(out, ".line {}", inst_line)?;
+            line = Some(inst_line);
+        }
+
+        write!(out, "    ")?;
+        write_inst(out, heap, globals, &inst, offset, chunk)?;
+        writeln!(out)?;
+    }
+    if offset < chunk.code.len() {
+        writeln!(out, "; malformed bytecode from offset {}", offset)?;
+    }
+    // A jump may target the very end of the code.
+    if targets.contains(&offset) {
+        writeln!(out, "{}:", label(offset))?;
+    }
+
+    writeln!(out, ".end")
+}
@@ -1,14 +1,75 @@
+use crate::asm::{self, AsmError};
 use crate::compiler::Compiler;
-use crate::vm::{VM, InterpretResult};
+use crate::vm::{VM, InterpretResult, RuntimeError};
 use crate::debug;
+use crate::native;
+use crate::obj::{Function, NativeFn};
+use crate::serialize::{self, LoadError};
+use crate::heap::Heap;
+use crate::value::Value;
 
+/// A long-lived interpreter session. Every call to `interpret` compiles the
+/// source against the same VM, so globals survive from one call to the next.
 pub struct Driver {
     debug_mode: bool,
+    vm: VM,
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Driver {
     pub fn new() -> Driver {
-        Driver { debug_mode: false }
+        let mut vm = VM::new();
+        native::define_std_natives(&mut vm);
+        Driver { debug_mode: false, vm }
+    }
+
+    /// Make a host function callable from scripts run by this driver.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.vm.define_native(name, arity, function);
+    }
+
+    /// Limit the number of values on the VM stack.
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.vm.set_max_stack(max_stack);
+    }
+
+    /// Limit the depth of nested calls.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.vm.set_max_frames(max_frames);
+    }
+
+    pub fn heap(&self) -> &Heap {
+        self.vm.heap()
+    }
+
+    /// The value of the global `name`, if it is defined.
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        let heap = self.vm.heap();
+        self.vm.globals().iter()
+            .find(|&(global, _)| heap.get(global).as_str() == Some(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Collect garbage before every allocation, to shake out objects that
+    /// are missing from the roots.
+    pub fn gc_stress(&mut self, stress: bool) {
+        self.vm.heap_mut().set_stress(stress);
+    }
+
+    /// Set the ratio between the heap size that triggers the next collection
+    /// and the bytes still live after the last one.
+    pub fn set_gc_grow_factor(&mut self, grow_factor: f64) {
+        self.vm.heap_mut().set_grow_factor(grow_factor);
+    }
+
+    /// The runtime error that aborted the last call to `interpret`, if any.
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.vm.last_error()
     }
 
     pub fn debug(&mut self) {
@@ -19,23 +80,77 @@ impl Driver {
         self.debug_mode = false;
     }
 
-    pub fn interpret(&self, source: String) -> InterpretResult {
-        let mut compiler = Compiler::new(source);
+    pub fn interpret(&mut self, source: String) -> InterpretResult {
+        match self.compile_function(source) {
+            Some(function) => self.run(function),
+            None => InterpretResult::CompileError,
+        }
+    }
 
-        let comp_res = compiler.compile();
+    /// Compile `source` to the `.loxc` format, without running it. Returns
+    /// `None` if any compile error has been reported.
+    pub fn compile(&mut self, source: String) -> Option<Vec<u8>> {
+        let function = self.compile_function(source)?;
+        Some(serialize::serialize(self.vm.heap(), self.vm.globals(), &function))
+    }
 
-        if !comp_res {
-            return InterpretResult::CompileError;
-        }
+    /// Run a script compiled to the `.loxc` format.
+    pub fn interpret_compiled(&mut self, bytes: &[u8]) -> Result<InterpretResult, LoadError> {
+        let (heap, globals) = self.vm.heap_and_globals_mut();
+        let function = serialize::deserialize(bytes, heap, globals)?;
+        Ok(self.run(function))
+    }
+
+    /// Assemble `source`, in the syntax documented in `asm`, and run it.
+    pub fn interpret_asm(&mut self, source: &str) -> Result<InterpretResult, AsmError> {
+        let (heap, globals) = self.vm.heap_and_globals_mut();
+        let function = asm::assemble(source, heap, globals)?;
+        Ok(self.run(function))
+    }
 
-        let mut vm = VM::new(compiler.current_chunk);
+    /// Assemble `source` to the `.loxc` format, without running it.
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, AsmError> {
+        let (heap, globals) = self.vm.heap_and_globals_mut();
+        let function = asm::assemble(source, heap, globals)?;
+        Ok(serialize::serialize(self.vm.heap(), self.vm.globals(), &function))
+    }
+
+    /// Compile `source` and return its assembly, without running it. Returns
+    /// `None` if any compile error has been reported.
+    pub fn disassemble(&mut self, source: String) -> Option<String> {
+        let function = self.compile_function(source)?;
+        Some(self.disassemble_function(&function))
+    }
+
+    /// The assembly of a script in the `.loxc` format.
+    pub fn disassemble_compiled(&mut self, bytes: &[u8]) -> Result<String, LoadError> {
+        let (heap, globals) = self.vm.heap_and_globals_mut();
+        let function = serialize::deserialize(bytes, heap, globals)?;
+        Ok(self.disassemble_function(&function))
+    }
+
+    fn disassemble_function(&self, function: &Function) -> String {
+        let mut out = String::new();
+        debug::disassemble(&mut out, self.vm.heap(), self.vm.globals(), function)
+            .expect("Writing to a string cannot fail");
+        out
+    }
+
+    fn compile_function(&mut self, source: String) -> Option<Function> {
+        let (heap, globals) = self.vm.heap_and_globals_mut();
+        Compiler::new(source, heap, globals).compile()
+    }
 
+    fn run(&mut self, function: Function) -> InterpretResult {
         if self.debug_mode {
-            vm.trace_on();
-            debug::disassemble_chunk(&vm.chunk, "code");
+            self.vm.trace_on();
+            print!("{}", self.disassemble_function(&function));
+        } else {
+            self.vm.trace_off();
         }
 
-        vm.run()
+        self.vm.load(function);
+        self.vm.run()
     }
 }
 
@@ -0,0 +1,84 @@
+use crate::heap::{Heap, ObjRef};
+use crate::value::Value;
+
+use std::collections::HashMap;
+
+/// Global variables, resolved to slots at compile time so that the VM reads
+/// and writes them by index.
+///
+/// A slot is allocated the first time a name is compiled, before the global
+/// is defined, and is never freed: a function may refer to a global that only
+/// a later script defines.
+#[derive(Debug, Default)]
+pub struct Globals {
+    /// Interned name of every slot, for error messages and reflection.
+    names: Vec<ObjRef>,
+    /// Value of every slot, `None` while the global is undefined.
+    values: Vec<Option<Value>>,
+    slots: HashMap<ObjRef, usize>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals { names: Vec::new(), values: Vec::new(), slots: HashMap::new() }
+    }
+
+    /// The slot of the global `name`, allocated if the name is new.
+    pub fn resolve(&mut self, name: ObjRef) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            return slot;
+        }
+
+        self.names.push(name);
+        self.values.push(None);
+        self.slots.insert(name, self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    /// The slot of the global `name`, if any code has referred to it.
+    pub fn lookup(&self, name: ObjRef) -> Option<usize> {
+        self.slots.get(&name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> Option<ObjRef> {
+        self.names.get(slot).copied()
+    }
+
+    /// The value of the global in `slot`, or `None` if it is undefined.
+    pub fn get(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot).and_then(Option::as_ref)
+    }
+
+    pub fn is_defined(&self, slot: usize) -> bool {
+        self.get(slot).is_some()
+    }
+
+    pub fn set(&mut self, slot: usize, v: Value) {
+        self.values[slot] = Some(v);
+    }
+
+    /// Number of slots, defined or not.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The defined globals with their names, in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, &Value)> {
+        self.names.iter().zip(&self.values).filter_map(|(&name, v)| v.as_ref().map(|v| (name, v)))
+    }
+
+    /// Mark every name and value as a root of a collection. Names of undefined
+    /// globals are kept as well, since compiled code may still report them.
+    pub fn mark(&self, heap: &mut Heap) {
+        for &name in &self.names {
+            heap.mark_object(name);
+        }
+        for v in self.values.iter().flatten() {
+            heap.mark_value(v);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::obj::Obj;
+use crate::value::Value;
+
+use std::collections::HashMap;
+
+/// Bytes that may be allocated before the first collection.
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+/// Default ratio between the threshold of the next collection and the bytes
+/// still live after the last one.
+pub const GC_GROW_FACTOR: f64 = 2.0;
+
+/// FNV-1a hash of a string, cached in its `Obj::Str`.
+pub fn hash_string(s: &str) -> u32 {
+    s.bytes().fold(2166136261u32, |hash, b| (hash ^ b as u32).wrapping_mul(16777619))
+}
+
+/// Handle to an object on a `Heap`. It stays valid as long as the object is
+/// reachable from the roots whenever a collection runs.
+#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+/// Storage for every object of a VM, reclaimed by mark and sweep.
+///
+/// Allocation never collects by itself: the owner checks `should_collect`,
+/// marks its roots with `mark_value` and `mark_object`, then calls `collect`
+/// to trace everything reachable from them and free the rest.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    /// Slots of freed objects, reused by later allocations.
+    free: Vec<usize>,
+    /// Marked objects whose references have not been traced yet.
+    gray: Vec<ObjRef>,
+    /// Every live string by hash, so that equal strings are a single object
+    /// and compare by reference. Sweeping drops the strings that die.
+    strings: HashMap<u32, Vec<ObjRef>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    grow_factor: f64,
+    /// Collect before every allocation, to shake out missing roots.
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            grow_factor: GC_GROW_FACTOR,
+            stress: false,
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = Some(obj);
+                ObjRef(idx)
+            },
+            None => {
+                self.objects.push(Some(obj));
+                self.marks.push(false);
+                ObjRef(self.objects.len() - 1)
+            },
+        }
+    }
+
+    /// The string object with contents `s`, allocated if it does not exist
+    /// yet. Strings must not be allocated any other way.
+    pub fn intern(&mut self, s: String) -> ObjRef {
+        let hash = hash_string(&s);
+
+        if let Some(candidates) = self.strings.get(&hash) {
+            for &r in candidates {
+                if self.get(r).as_str() == Some(s.as_str()) {
+                    return r;
+                }
+            }
+        }
+
+        let r = self.alloc(Obj::Str { data: s, hash });
+        self.strings.entry(hash).or_default().push(r);
+        r
+    }
+
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        self.objects[r.0].as_ref().expect("Dangling object reference")
+    }
+
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        self.objects[r.0].as_mut().expect("Dangling object reference")
+    }
+
+    /// The contents of `v` if it is a string.
+    pub fn as_string(&self, v: &Value) -> Option<&str> {
+        v.as_obj().and_then(|r| self.get(r).as_str())
+    }
+
+    pub fn is_string(&self, v: &Value) -> bool {
+        self.as_string(v).is_some()
+    }
+
+    pub fn set_grow_factor(&mut self, grow_factor: f64) {
+        self.grow_factor = grow_factor;
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Number of objects currently allocated.
+    pub fn live_objects(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, v: &Value) {
+        if let Some(r) = v.as_obj() {
+            self.mark_object(r);
+        }
+    }
+
+    pub fn mark_object(&mut self, r: ObjRef) {
+        if !self.marks[r.0] {
+            self.marks[r.0] = true;
+            self.gray.push(r);
+        }
+    }
+
+    /// Free every object not reachable from the marked roots, and schedule the
+    /// next collection relative to the bytes still live.
+    pub fn collect(&mut self) {
+        self.trace_references();
+        self.sweep();
+        self.next_gc = ((self.bytes_allocated as f64 * self.grow_factor) as usize).max(INITIAL_GC_THRESHOLD);
+    }
+
+    fn trace_references(&mut self) {
+        let mut refs = Vec::new();
+
+        while let Some(r) = self.gray.pop() {
+            self.get(r).trace(&mut refs);
+            for r in refs.drain(..) {
+                self.mark_object(r);
+            }
+        }
+    }
+
+    fn forget_string(&mut self, hash: u32, r: ObjRef) {
+        if let Some(candidates) = self.strings.get_mut(&hash) {
+            candidates.retain(|&candidate| candidate != r);
+            if candidates.is_empty() {
+                self.strings.remove(&hash);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        self.bytes_allocated = 0;
+
+        for idx in 0..self.objects.len() {
+            if self.marks[idx] {
+                self.marks[idx] = false;
+                if let Some(obj) = &self.objects[idx] {
+                    self.bytes_allocated += obj.size();
+                }
+            } else if let Some(obj) = self.objects[idx].take() {
+                if let Obj::Str { hash, .. } = obj {
+                    self.forget_string(hash, ObjRef(idx));
+                }
+                self.free.push(idx);
+            }
+        }
+    }
+}
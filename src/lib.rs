@@ -0,0 +1,16 @@
+pub mod chunk;
+pub mod debug;
+pub mod value;
+pub mod vm;
+pub mod scanner;
+pub mod parser;
+pub mod compiler;
+pub mod span;
+pub mod driver;
+pub mod obj;
+pub mod heap;
+pub mod native;
+pub mod globals;
+pub mod serialize;
+pub mod verify;
+pub mod asm;
@@ -1,57 +1,178 @@
-pub mod chunk;
-pub mod debug;
-pub mod value;
-pub mod vm;
-pub mod scanner;
-pub mod parser;
-pub mod compiler;
-pub mod span;
-pub mod driver;
-pub mod obj;
-
 use std::io;
 use std::env;
+use std::fs;
+use std::process;
 use std::io::Write;
+use std::path::Path;
 
 // use compiler::Compiler;
-use driver::Driver;
-
-use crate::vm::InterpretResult;
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+use rlox::serialize;
 
-fn repl() {
+fn repl(mut driver: Driver) {
     let mut line = String::new();
+    driver.debug();
+
     loop {
         print!("> ");
         line.clear();
         io::stdout().flush().expect("Fail to flush stdout!");
-        io::stdin().read_line(&mut line).expect("Fail to read from stdin!");
+        let nread = io::stdin().read_line(&mut line).expect("Fail to read from stdin!");
 
         // let mut compiler = Compiler::new(line.clone());
         // compiler.compile();
 
-        if line == ":q\n" {
+        if nread == 0 || line.trim_end() == ":q" {
             break;
         }
 
-        let mut driver = Driver::new();
-        driver.debug();
-
         let res = driver.interpret(line.clone());
         match res {
             InterpretResult::Ok => {},
+            InterpretResult::RuntimeError => report_runtime_error(&driver),
             _ => { println!("!!!!!! Error: {:?}", res); }
         }
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        println!("Hola!");
-        println!("I haven't been programmed to compile an entire file. Stay tuned!")
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read file \"{}\": {}", path, err);
+            process::exit(74);
+        }
+    }
+}
+
+fn read_source(path: &str, bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Could not read file \"{}\": {}", path, err);
+            process::exit(74);
+        }
+    }
+}
+
+/// Run a source file, or a script compiled by `rlox compile`.
+fn run_file(mut driver: Driver, path: &str) {
+    let bytes = read_file(path);
+
+    let res = if serialize::is_compiled(&bytes) {
+        match driver.interpret_compiled(&bytes) {
+            Ok(res) => res,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(65);
+            },
+        }
+    } else {
+        driver.interpret(read_source(path, bytes))
+    };
+
+    match res {
+        InterpretResult::Ok => {},
+        InterpretResult::CompileError => process::exit(65),
+        InterpretResult::RuntimeError => {
+            report_runtime_error(&driver);
+            process::exit(70);
+        },
+    }
+}
+
+fn compile_file(mut driver: Driver, path: &str, output: &str) {
+    let source = read_source(path, read_file(path));
+
+    let bytes = match driver.compile(source) {
+        Some(bytes) => bytes,
+        None => process::exit(65),
+    };
+
+    write_file(output, bytes);
+}
+
+/// Assemble a file in the syntax printed by `rlox disasm` to the `.loxc`
+/// format.
+fn assemble_file(mut driver: Driver, path: &str, output: &str) {
+    let source = read_source(path, read_file(path));
+
+    let bytes = match driver.assemble(&source) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(65);
+        },
+    };
+
+    write_file(output, bytes);
+}
+
+/// Print the assembly of a source file, or of a script compiled by
+/// `rlox compile`.
+fn disassemble_file(mut driver: Driver, path: &str) {
+    let bytes = read_file(path);
+
+    let text = if serialize::is_compiled(&bytes) {
+        match driver.disassemble_compiled(&bytes) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(65);
+            },
+        }
     } else {
-        println!("Usage: rlox [path]");
+        match driver.disassemble(read_source(path, bytes)) {
+            Some(text) => text,
+            None => process::exit(65),
+        }
+    };
+
+    print!("{}", text);
+}
+
+fn write_file(path: &str, bytes: Vec<u8>) {
+    if let Err(err) = fs::write(path, bytes) {
+        eprintln!("Could not write file \"{}\": {}", path, err);
+        process::exit(74);
+    }
+}
+
+fn report_runtime_error(driver: &Driver) {
+    if let Some(err) = driver.last_error() {
+        eprintln!("{}", err);
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut driver = Driver::new();
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--gc-stress") {
+        args.remove(pos);
+        driver.gc_stress(true);
+    }
+
+    match args.as_slice() {
+        [] => repl(driver),
+        [command, path] if command == "compile" => {
+            let output = Path::new(path).with_extension("loxc");
+            compile_file(driver, path, &output.to_string_lossy());
+        },
+        [command, path, flag, output] if command == "compile" && flag == "-o" => compile_file(driver, path, output),
+        [command, path] if command == "asm" => {
+            let output = Path::new(path).with_extension("loxc");
+            assemble_file(driver, path, &output.to_string_lossy());
+        },
+        [command, path, flag, output] if command == "asm" && flag == "-o" => assemble_file(driver, path, output),
+        [command, path] if command == "disasm" => disassemble_file(driver, path),
+        [path] => run_file(driver, path),
+        _ => {
+            println!("Usage: rlox [--gc-stress] [path]");
+            println!("       rlox compile <path> [-o <output>]");
+            println!("       rlox asm <path> [-o <output>]");
+            println!("       rlox disasm <path>");
+        },
     }
 }
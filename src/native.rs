@@ -0,0 +1,16 @@
+use crate::heap::Heap;
+use crate::value::Value;
+use crate::vm::VM;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Register the builtins available to every Lox program.
+pub fn define_std_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, native_clock);
+}
+
+/// Seconds since the Unix epoch.
+fn native_clock(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|err| err.to_string())?;
+    Ok(Value::DOUBLE { data: now.as_secs_f64() })
+}
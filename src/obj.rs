@@ -1,4 +1,237 @@
+use crate::chunk::{Chunk, LineRun};
+use crate::heap::{Heap, ObjRef};
+use crate::value::Value;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
 #[derive(Debug)]
 pub enum Obj {
-    Str { data: String },
+    /// An interned string, created through `Heap::intern`.
+    Str { data: String, hash: u32 },
+    Function { data: Function },
+    Closure { data: Closure },
+    Upvalue { data: Upvalue },
+    Class { data: Class },
+    Instance { data: Instance },
+    BoundMethod { data: BoundMethod },
+    Native { data: Native },
+}
+
+/// Where a closure finds one of its captured variables when it is created.
+#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueDesc {
+    /// Whether the variable is a local of the enclosing function, rather than
+    /// one of its upvalues.
+    pub is_local: bool,
+    /// Local slot or upvalue index in the enclosing function.
+    pub index: usize,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    /// `None` for the top-level script.
+    pub name: Option<String>,
+    pub upvalues: Vec<UpvalueDesc>,
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Function {
+        Function { arity: 0, chunk: Chunk::new(), name, upvalues: Vec::new() }
+    }
+}
+
+/// A function together with the variables it captured at creation time.
+#[derive(Debug)]
+pub struct Closure {
+    /// The `Obj::Function` being closed over.
+    pub function: ObjRef,
+    /// The captured `Obj::Upvalue`s, in the order of `Function::upvalues`.
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A captured variable. It refers to a stack slot while the variable is in
+/// scope, and owns the value once the variable has gone out of scope.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open { slot: usize },
+    Closed { value: Value },
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    /// Methods by interned name, each an `Obj::Closure`.
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class { name, methods: HashMap::new() }
+    }
+
+    pub fn find_method(&self, name: ObjRef) -> Option<ObjRef> {
+        self.methods.get(&name).copied()
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    /// The `Obj::Class` this is an instance of.
+    pub class: ObjRef,
+    /// Fields by interned name.
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Instance {
+        Instance { class, fields: HashMap::new() }
+    }
+}
+
+/// A method read off an instance, remembering the instance it was read from.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    /// The `Obj::Closure` of the method.
+    pub method: ObjRef,
+}
+
+/// A function implemented by the host. It receives the heap, to read and
+/// allocate objects, and the arguments of the call, whose number has already
+/// been checked against the declared arity. Failures are reported as runtime
+/// errors.
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl Obj {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Obj::Str { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Function> {
+        match self {
+            Obj::Function { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Closure> {
+        match self {
+            Obj::Closure { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&Upvalue> {
+        match self {
+            Obj::Upvalue { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self) -> Option<&mut Upvalue> {
+        match self {
+            Obj::Upvalue { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Class> {
+        match self {
+            Obj::Class { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_class_mut(&mut self) -> Option<&mut Class> {
+        match self {
+            Obj::Class { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match self {
+            Obj::Instance { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> Option<&mut Instance> {
+        match self {
+            Obj::Instance { data } => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Approximate number of bytes owned by the object, which paces the
+    /// garbage collector.
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Obj::Str { data, .. } => data.len(),
+            Obj::Function { data } => {
+                let chunk = &data.chunk;
+                chunk.code.len()
+                    + chunk.value_array.data.len() * mem::size_of::<Value>()
+                    + chunk.lines.len() * mem::size_of::<LineRun>()
+                    + data.upvalues.len() * mem::size_of::<UpvalueDesc>()
+            },
+            Obj::Closure { data } => data.upvalues.len() * mem::size_of::<ObjRef>(),
+            Obj::Class { data } => data.methods.len() * mem::size_of::<(ObjRef, ObjRef)>(),
+            Obj::Instance { data } => data.fields.len() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::Upvalue { .. } | Obj::BoundMethod { .. } | Obj::Native { .. } => 0,
+        };
+        mem::size_of::<Obj>() + owned
+    }
+
+    /// Push the objects directly referenced by this one.
+    pub fn trace(&self, refs: &mut Vec<ObjRef>) {
+        match self {
+            Obj::Str { .. } | Obj::Native { .. } => {},
+            Obj::Function { data } => {
+                refs.extend(data.chunk.value_array.data.iter().filter_map(|v| v.as_obj()));
+            },
+            Obj::Closure { data } => {
+                refs.push(data.function);
+                refs.extend(&data.upvalues);
+            },
+            Obj::Upvalue { data } => {
+                if let Upvalue::Closed { value } = data {
+                    refs.extend(value.as_obj());
+                }
+            },
+            Obj::Class { data } => {
+                refs.extend(data.methods.keys());
+                refs.extend(data.methods.values());
+            },
+            Obj::Instance { data } => {
+                refs.push(data.class);
+                refs.extend(data.fields.keys());
+                refs.extend(data.fields.values().filter_map(|v| v.as_obj()));
+            },
+            Obj::BoundMethod { data } => {
+                refs.extend(data.receiver.as_obj());
+                refs.push(data.method);
+            },
+        }
+    }
 }
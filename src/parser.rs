@@ -1,19 +1,29 @@
-use crate::chunk::{Inst, KMethod};
+use crate::chunk::{Inst, JUMP_LEN, SHORT_MAX};
 use crate::scanner::{Token, TokenType};
-use crate::compiler::Compiler;
+use crate::compiler::{Compiler, ClassState, FunctionType, Local, LOCALS_MAX};
 use crate::span::Span;
 use crate::value::Value;
-use crate::obj::Obj;
+use crate::obj::{Obj, UpvalueDesc};
 
-use std::collections::HashMap;
-use std::rc::Rc;
+/// Maximum number of parameters of a function, and of arguments in a call.
+const ARGS_MAX: usize = 255;
+/// Maximum nesting of declarations, statements and expressions, which keeps
+/// the recursive descent from overflowing the native stack.
+const NESTING_MAX: usize = 256;
 
 pub struct ParserState {
     pub current: Token,
     pub previous: Token,
     pub had_error: bool,
     pub panic_mode: bool,
-    pub parsing_table: ParseTable,
+    /// Current nesting of declarations, statements and expressions.
+    pub depth: usize,
+}
+
+impl Default for ParserState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ParserState {
@@ -23,12 +33,12 @@ impl ParserState {
             previous: empty_token(),
             had_error: false,
             panic_mode: false,
-            parsing_table: ParseRule::make_rules()
+            depth: 0,
         }
     }
 
-    pub fn get_rule(&self, tp: TokenType) -> &ParseRule {
-        &self.parsing_table[&tp]
+    pub fn get_rule(&self, tp: TokenType) -> ParseRule {
+        ParseRule::for_token(tp)
     }
 }
 
@@ -76,7 +86,7 @@ pub fn advance(compiler: &mut Compiler) {
             break;
         }
 
-        emit_error_at_current(compiler, &tok.content);
+        error_at(&mut compiler.parser, &tok, &tok.content);
     }
 }
 
@@ -85,7 +95,7 @@ pub fn consume(compiler: &mut Compiler, tp: TokenType, msg: &str) {
         advance(compiler);
     } else {
         let current_token: Token = compiler.parser.current.clone();
-        error_at(&mut compiler.parser, &current_token, &msg[..]);
+        error_at(&mut compiler.parser, &current_token, msg);
     }
 }
 
@@ -101,42 +111,83 @@ pub fn try_consume(compiler: &mut Compiler, tp: TokenType) -> bool {
 }
 
 fn emit_str(compiler: &mut Compiler, s: String) {
-    let r = Rc::new(Obj::Str { data: s });
+    let r = compiler.heap.intern(s);
     let v = Value::OBJ { data: r };
 
     emit_constant(compiler, v)
 }
 
+/// The slot of the global variable `name`.
+fn resolve_global(compiler: &mut Compiler, name: String) -> usize {
+    let name = compiler.heap.intern(name);
+    let slot = compiler.globals.resolve(name);
+    if slot > SHORT_MAX {
+        emit_error(compiler, "Too many global variables.");
+        return 0;
+    }
+    slot
+}
+
 fn make_str(compiler: &mut Compiler, s: String) -> usize {
-    let r = Rc::new(Obj::Str { data: s });
+    let r = compiler.heap.intern(s);
     let v = Value::OBJ { data: r };
 
-    compiler.current_chunk.value_array.add_constant(v)
+    make_constant(compiler, v)
 }
 
-fn emit_constant(compiler: &mut Compiler, v: Value) {
-    let idx = compiler.current_chunk.value_array.add_constant(v);
-    compiler.emit_inst(Inst::CONSTANT { idx });
+/// The index of `v` in the constants of the current chunk. Equal constants
+/// share a single index.
+pub fn make_constant(compiler: &mut Compiler, v: Value) -> usize {
+    match compiler.current_chunk().value_array.add_constant(v) {
+        Some(idx) => idx,
+        None => {
+            emit_error(compiler, "Too many constants in one chunk.");
+            0
+        },
+    }
 }
 
-fn emit_print(compiler: &mut Compiler) {
-    compiler.emit_inst(Inst::OP_KCALL { tp: KMethod::Print })
+fn emit_constant(compiler: &mut Compiler, v: Value) {
+    let idx = make_constant(compiler, v);
+    compiler.emit_inst(Inst::CONSTANT { idx });
 }
 
 pub fn parse_expression(compiler: &mut Compiler) {
     parse_prec(compiler, Precedence::Assignment);
 }
 
-pub fn parse_decl(compiler: &mut Compiler) {
-    if try_consume(compiler, TokenType::Var) {
-        parse_var_decl(compiler);
-    } else {
-        parse_stmt(compiler);
+/// Run `parse` one nesting level deeper. Past `NESTING_MAX` the rest of the
+/// source is skipped, as there is no sensible place to resume parsing.
+fn nested(compiler: &mut Compiler, parse: impl FnOnce(&mut Compiler)) {
+    if compiler.parser.depth >= NESTING_MAX {
+        emit_error_at_current(compiler, "Too much nesting.");
+        while !check_next(compiler, TokenType::EOF) {
+            advance(compiler);
+        }
+        return;
     }
+
+    compiler.parser.depth += 1;
+    parse(compiler);
+    compiler.parser.depth -= 1;
+}
+
+pub fn parse_decl(compiler: &mut Compiler) {
+    nested(compiler, |compiler| {
+        if try_consume(compiler, TokenType::Var) {
+            parse_var_decl(compiler);
+        } else if try_consume(compiler, TokenType::Fun) {
+            parse_fun_decl(compiler);
+        } else if try_consume(compiler, TokenType::Class) {
+            parse_class_decl(compiler);
+        } else {
+            parse_stmt(compiler);
+        }
+    });
 }
 
 fn parse_var_decl(compiler: &mut Compiler) {
-    let varname_idx = parse_var_name(compiler, "Expecting variable name after `var`");
+    let global = parse_var_name(compiler, "Expecting variable name after `var`");
 
     if try_consume(compiler, TokenType::Equal) {
         parse_expression(compiler);
@@ -146,25 +197,378 @@ fn parse_var_decl(compiler: &mut Compiler) {
 
     consume(compiler, TokenType::SemiColon, "Expecting ';' after variable decl");
 
-    define_variable(compiler, varname_idx);
+    define_variable(compiler, global);
+}
+
+fn parse_class_decl(compiler: &mut Compiler) {
+    let global = parse_var_name(compiler, "Expect class name.");
+    let class_name = compiler.parser.previous.content.clone();
+    let name_idx = make_str(compiler, class_name.clone());
+
+    compiler.emit_inst(Inst::OP_CLASS { name_idx });
+    define_variable(compiler, global);
+
+    compiler.classes.push(ClassState { has_superclass: false });
+
+    if try_consume(compiler, TokenType::Less) {
+        consume(compiler, TokenType::Identifier, "Expect superclass name.");
+        parse_variable(compiler, false);
+
+        if compiler.parser.previous.content == class_name {
+            emit_error(compiler, "A class can't inherit from itself.");
+        }
+
+        // The superclass lives in a local named `super` of a scope wrapping the
+        // class body, so that methods capture it as an upvalue.
+        begin_scope(compiler);
+        add_local(compiler, "super".to_string());
+        define_variable(compiler, 0);
+
+        named_variable(compiler, class_name.clone(), false);
+        compiler.emit_inst(Inst::OP_INHERIT);
+        if let Some(class) = compiler.classes.last_mut() {
+            class.has_superclass = true;
+        }
+    }
+
+    // Keep the class on the stack while its methods are attached to it.
+    named_variable(compiler, class_name, false);
+    consume(compiler, TokenType::LeftBrace, "Expect '{' before class body.");
+    while !check_next(compiler, TokenType::RightBrace) && !check_next(compiler, TokenType::EOF) {
+        parse_method(compiler);
+    }
+    consume(compiler, TokenType::RightBrace, "Expect '}' after class body.");
+    compiler.emit_inst(Inst::OP_POP);
+
+    if compiler.classes.last().is_some_and(|class| class.has_superclass) {
+        end_scope(compiler);
+    }
+
+    compiler.classes.pop();
+}
+
+fn parse_method(compiler: &mut Compiler) {
+    consume(compiler, TokenType::Identifier, "Expect method name.");
+    let method_name = compiler.parser.previous.content.clone();
+    let fn_type = if method_name == "init" { FunctionType::Initializer } else { FunctionType::Method };
+    let name_idx = make_str(compiler, method_name);
+
+    parse_function(compiler, fn_type);
+    compiler.emit_inst(Inst::OP_METHOD { name_idx });
 }
 
-fn define_variable(compiler: &mut Compiler, varname_idx: usize) {
-    compiler.emit_inst(Inst::OP_DEFINE_GLOBAL { name_idx: varname_idx });
+fn parse_fun_decl(compiler: &mut Compiler) {
+    let global = parse_var_name(compiler, "Expect function name.");
+    // A function may refer to itself in its body, so it is usable right away.
+    mark_initialized(compiler);
+    parse_function(compiler, FunctionType::Function);
+    define_variable(compiler, global);
 }
 
+fn parse_function(compiler: &mut Compiler, fn_type: FunctionType) {
+    let name = compiler.parser.previous.content.clone();
+    compiler.begin_function(fn_type, name);
+    begin_scope(compiler);
+
+    consume(compiler, TokenType::LeftParen, "Expect '(' after function name.");
+    if !check_next(compiler, TokenType::RightParen) {
+        loop {
+            compiler.current_mut().function.arity += 1;
+            if compiler.current().function.arity > ARGS_MAX {
+                emit_error_at_current(compiler, "Can't have more than 255 parameters.");
+            }
+
+            parse_var_name(compiler, "Expect parameter name.");
+            define_variable(compiler, 0);
+
+            if !try_consume(compiler, TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    consume(compiler, TokenType::RightParen, "Expect ')' after parameters.");
+    consume(compiler, TokenType::LeftBrace, "Expect '{' before function body.");
+    parse_block(compiler);
+
+    // No end_scope: the whole frame, locals included, is discarded on return.
+    let function = compiler.end_function();
+    let v = Value::OBJ { data: compiler.heap.alloc(Obj::Function { data: function }) };
+    let idx = make_constant(compiler, v);
+    compiler.emit_inst(Inst::OP_CLOSURE { idx });
+}
+
+fn define_variable(compiler: &mut Compiler, global: usize) {
+    if compiler.current().scope_depth > 0 {
+        // The value is already sitting in the local's stack slot.
+        mark_initialized(compiler);
+        return;
+    }
+
+    compiler.emit_inst(Inst::OP_DEFINE_GLOBAL { slot: global });
+}
+
+/// Parse a variable name. Returns the slot of the variable for globals, and a
+/// dummy index for locals, which are addressed by stack slot instead.
 fn parse_var_name(compiler: &mut Compiler, err_msg: &str) -> usize {
     consume(compiler, TokenType::Identifier, err_msg);
+
+    if compiler.current().scope_depth > 0 {
+        declare_local(compiler);
+        return 0;
+    }
+
     let var_name = compiler.parser.previous.content.clone();
-    make_str(compiler, var_name)
+    resolve_global(compiler, var_name)
+}
+
+fn declare_local(compiler: &mut Compiler) {
+    let name = compiler.parser.previous.content.clone();
+    add_local(compiler, name);
+}
+
+fn add_local(compiler: &mut Compiler, name: String) {
+    let redeclared = compiler.current().locals.iter().rev()
+        .take_while(|local| local.depth.is_none_or(|depth| depth >= compiler.current().scope_depth))
+        .any(|local| local.name == name);
+    if redeclared {
+        emit_error(compiler, "Already a variable with this name in this scope.");
+    }
+
+    if compiler.current().locals.len() >= LOCALS_MAX {
+        emit_error(compiler, "Too many local variables in function.");
+        return;
+    }
+
+    compiler.current_mut().locals.push(Local { name, depth: None, is_captured: false });
+}
+
+fn mark_initialized(compiler: &mut Compiler) {
+    let depth = compiler.current().scope_depth;
+    if depth == 0 {
+        return;
+    }
+
+    if let Some(local) = compiler.current_mut().locals.last_mut() {
+        local.depth = Some(depth);
+    }
+}
+
+/// Resolve `name` among the locals of the function compiled by `states[level]`.
+fn resolve_local(compiler: &mut Compiler, level: usize, name: &str) -> Option<usize> {
+    let found = compiler.states[level].locals.iter().enumerate().rev()
+        .find(|(_, local)| local.name == name)
+        .map(|(slot, local)| (slot, local.depth.is_none()));
+
+    match found {
+        Option::None => None,
+        Option::Some((slot, uninitialized)) => {
+            if uninitialized {
+                emit_error(compiler, "Can't read local variable in its own initializer.");
+            }
+            Some(slot)
+        },
+    }
+}
+
+/// Resolve `name` as a variable captured by the function compiled by
+/// `states[level]`, capturing it in every function in between.
+fn resolve_upvalue(compiler: &mut Compiler, level: usize, name: &str) -> Option<usize> {
+    if level == 0 {
+        return None;
+    }
+
+    let desc = if let Some(slot) = resolve_local(compiler, level - 1, name) {
+        compiler.states[level - 1].locals[slot].is_captured = true;
+        UpvalueDesc { is_local: true, index: slot }
+    } else {
+        let index = resolve_upvalue(compiler, level - 1, name)?;
+        UpvalueDesc { is_local: false, index }
+    };
+
+    match compiler.states[level].add_upvalue(desc) {
+        Some(idx) => Some(idx),
+        None => {
+            emit_error(compiler, "Too many closure variables in function.");
+            Some(0)
+        }
+    }
+}
+
+fn begin_scope(compiler: &mut Compiler) {
+    compiler.current_mut().scope_depth += 1;
+}
+
+fn end_scope(compiler: &mut Compiler) {
+    compiler.current_mut().scope_depth -= 1;
+
+    while compiler.current().locals.last().is_some_and(|local| local.depth.is_none_or(|depth| depth > compiler.current().scope_depth)) {
+        let local = compiler.current_mut().locals.pop().expect("Checked by the loop condition");
+        if local.is_captured {
+            compiler.emit_inst(Inst::OP_CLOSE_UPVALUE);
+        } else {
+            compiler.emit_inst(Inst::OP_POP);
+        }
+    }
 }
 
 pub fn parse_stmt(compiler: &mut Compiler) {
-    if try_consume(compiler, TokenType::Print) {
-        parse_print_stmt(compiler);
+    nested(compiler, |compiler| {
+        if try_consume(compiler, TokenType::Print) {
+            parse_print_stmt(compiler);
+        } else if try_consume(compiler, TokenType::If) {
+            parse_if_stmt(compiler);
+        } else if try_consume(compiler, TokenType::Return) {
+            parse_return_stmt(compiler);
+        } else if try_consume(compiler, TokenType::While) {
+            parse_while_stmt(compiler);
+        } else if try_consume(compiler, TokenType::For) {
+            parse_for_stmt(compiler);
+        } else if try_consume(compiler, TokenType::LeftBrace) {
+            begin_scope(compiler);
+            parse_block(compiler);
+            end_scope(compiler);
+        } else {
+            parse_expr_stmt(compiler);
+        }
+    });
+}
+
+fn parse_block(compiler: &mut Compiler) {
+    while !check_next(compiler, TokenType::RightBrace) && !check_next(compiler, TokenType::EOF) {
+        parse_decl(compiler);
+    }
+
+    consume(compiler, TokenType::RightBrace, "Expect '}' after block.");
+}
+
+/// Emit a forward jump to be patched later. Returns its offset.
+fn emit_jump(compiler: &mut Compiler, inst: Inst) -> usize {
+    compiler.emit_inst(inst);
+    compiler.current_chunk().code.len() - JUMP_LEN
+}
+
+/// Point the forward jump at offset `at` to the next instruction.
+fn patch_jump(compiler: &mut Compiler, at: usize) {
+    if compiler.current_chunk().code.len() - at - JUMP_LEN > SHORT_MAX {
+        emit_error(compiler, "Too much code to jump over.");
+        return;
+    }
+
+    compiler.current_chunk().patch_jump(at);
+}
+
+fn emit_loop(compiler: &mut Compiler, loop_start: usize) {
+    // The offset is taken relative to the instruction after the loop, which
+    // starts right after the loop instruction being emitted.
+    let offset = compiler.current_chunk().code.len() + JUMP_LEN - loop_start;
+    if offset > SHORT_MAX {
+        emit_error(compiler, "Loop body too large.");
+        return;
+    }
+
+    compiler.emit_inst(Inst::OP_LOOP { offset });
+}
+
+fn parse_if_stmt(compiler: &mut Compiler) {
+    consume(compiler, TokenType::LeftParen, "Expect '(' after 'if'.");
+    parse_expression(compiler);
+    consume(compiler, TokenType::RightParen, "Expect ')' after condition.");
+
+    let then_jump = emit_jump(compiler, Inst::OP_JUMP_IF_FALSE { offset: 0 });
+    compiler.emit_inst(Inst::OP_POP);
+    parse_stmt(compiler);
+
+    let else_jump = emit_jump(compiler, Inst::OP_JUMP { offset: 0 });
+    patch_jump(compiler, then_jump);
+    compiler.emit_inst(Inst::OP_POP);
+
+    if try_consume(compiler, TokenType::Else) {
+        parse_stmt(compiler);
+    }
+    patch_jump(compiler, else_jump);
+}
+
+fn parse_return_stmt(compiler: &mut Compiler) {
+    if compiler.current().fn_type == FunctionType::Script {
+        emit_error(compiler, "Can't return from top-level code.");
+    }
+
+    if try_consume(compiler, TokenType::SemiColon) {
+        compiler.emit_return();
+    } else {
+        if compiler.current().fn_type == FunctionType::Initializer {
+            emit_error(compiler, "Can't return a value from an initializer.");
+        }
+
+        parse_expression(compiler);
+        consume(compiler, TokenType::SemiColon, "Expect ';' after return value.");
+        compiler.emit_inst(Inst::RETURN);
+    }
+}
+
+fn parse_while_stmt(compiler: &mut Compiler) {
+    let loop_start = compiler.current_chunk().code.len();
+
+    consume(compiler, TokenType::LeftParen, "Expect '(' after 'while'.");
+    parse_expression(compiler);
+    consume(compiler, TokenType::RightParen, "Expect ')' after condition.");
+
+    let exit_jump = emit_jump(compiler, Inst::OP_JUMP_IF_FALSE { offset: 0 });
+    compiler.emit_inst(Inst::OP_POP);
+    parse_stmt(compiler);
+    emit_loop(compiler, loop_start);
+
+    patch_jump(compiler, exit_jump);
+    compiler.emit_inst(Inst::OP_POP);
+}
+
+fn parse_for_stmt(compiler: &mut Compiler) {
+    begin_scope(compiler);
+    consume(compiler, TokenType::LeftParen, "Expect '(' after 'for'.");
+
+    if try_consume(compiler, TokenType::SemiColon) {
+        // No initializer.
+    } else if try_consume(compiler, TokenType::Var) {
+        parse_var_decl(compiler);
     } else {
         parse_expr_stmt(compiler);
     }
+
+    let mut loop_start = compiler.current_chunk().code.len();
+
+    let mut exit_jump = None;
+    if !try_consume(compiler, TokenType::SemiColon) {
+        parse_expression(compiler);
+        consume(compiler, TokenType::SemiColon, "Expect ';' after loop condition.");
+
+        exit_jump = Some(emit_jump(compiler, Inst::OP_JUMP_IF_FALSE { offset: 0 }));
+        compiler.emit_inst(Inst::OP_POP);
+    }
+
+    if !try_consume(compiler, TokenType::RightParen) {
+        // The increment is compiled before the body but runs after it, so the
+        // body jumps over it and loops back to it at the end.
+        let body_jump = emit_jump(compiler, Inst::OP_JUMP { offset: 0 });
+        let increment_start = compiler.current_chunk().code.len();
+
+        parse_expression(compiler);
+        compiler.emit_inst(Inst::OP_POP);
+        consume(compiler, TokenType::RightParen, "Expect ')' after for clauses.");
+
+        emit_loop(compiler, loop_start);
+        loop_start = increment_start;
+        patch_jump(compiler, body_jump);
+    }
+
+    parse_stmt(compiler);
+    emit_loop(compiler, loop_start);
+
+    if let Some(exit_jump) = exit_jump {
+        patch_jump(compiler, exit_jump);
+        compiler.emit_inst(Inst::OP_POP);
+    }
+
+    end_scope(compiler);
 }
 
 fn parse_expr_stmt(compiler: &mut Compiler) {
@@ -176,62 +580,125 @@ fn parse_expr_stmt(compiler: &mut Compiler) {
 fn parse_print_stmt(compiler: &mut Compiler) {
     parse_expression(compiler);
     consume(compiler, TokenType::SemiColon, "Expect ';' at end of statement.");
-    emit_print(compiler);
+    compiler.emit_inst(Inst::OP_PRINT);
 }
 
 fn parse_prec(compiler: &mut Compiler, prec: Precedence) {
-    advance(compiler);
-    let prev = &compiler.parser.previous;
-    let prefix_fn = compiler.parser.get_rule(prev.tp).prefix;
-
-    match prefix_fn {
-        Option::None => {
-            emit_error(compiler, "Expect expression.");
-            return;
-        },
-        Option::Some(func) => {
-            func(compiler);
-        },
-    }
-
-    loop {
-        let infix_prec;
-        let infix_fn;
-        {
-            let infix_rule = compiler.parser.get_rule(compiler.parser.current.tp);
-            infix_prec = infix_rule.prec;
-            infix_fn = infix_rule.infix;
-        }
-
-        if prec > infix_prec {
-            break;
-        }
-
+    nested(compiler, |compiler| {
         advance(compiler);
+        let prev = &compiler.parser.previous;
+        let prefix_fn = compiler.parser.get_rule(prev.tp).prefix;
+        // Only an expression parsed at assignment precedence may be the target of `=`.
+        let can_assign = prec <= Precedence::Assignment;
 
-        match infix_fn {
+        match prefix_fn {
             Option::None => {
-                emit_error(compiler, "Expecting valid infix operator.");
+                emit_error(compiler, "Expect expression.");
+                return;
             },
             Option::Some(func) => {
-                func(compiler);
+                func(compiler, can_assign);
+            },
+        }
+
+        loop {
+            let infix_prec;
+            let infix_fn;
+            {
+                let infix_rule = compiler.parser.get_rule(compiler.parser.current.tp);
+                infix_prec = infix_rule.prec;
+                infix_fn = infix_rule.infix;
+            }
+
+            if prec > infix_prec {
+                break;
+            }
+
+            advance(compiler);
+
+            match infix_fn {
+                Option::None => {
+                    emit_error(compiler, "Expecting valid infix operator.");
+                },
+                Option::Some(func) => {
+                    func(compiler, can_assign);
+                }
             }
         }
-    }
+
+        if can_assign && try_consume(compiler, TokenType::Equal) {
+            emit_error(compiler, "Invalid assignment target.");
+        }
+    });
 }
 
-fn parse_number(compiler: &mut Compiler) {
-    let num: f64 = compiler.parser.previous.content.parse().expect("Can not parse number.");
-    emit_constant(compiler, Value::DOUBLE { data: num });
+fn parse_number(compiler: &mut Compiler, _can_assign: bool) {
+    match compiler.parser.previous.content.parse() {
+        Ok(num) => emit_constant(compiler, Value::DOUBLE { data: num }),
+        Err(_) => emit_error(compiler, "Invalid number literal."),
+    }
 }
 
-fn parse_variable(compiler: &mut Compiler) {
+fn parse_variable(compiler: &mut Compiler, can_assign: bool) {
     let vname: String = compiler.parser.previous.content.clone();
-    let vid = make_str(compiler, vname);
-    compiler.emit_inst(Inst::OP_GET_GLOBAL { name_idx: vid });
+    named_variable(compiler, vname, can_assign);
+}
+
+fn parse_this(compiler: &mut Compiler, _can_assign: bool) {
+    if compiler.classes.is_empty() {
+        emit_error(compiler, "Can't use 'this' outside of a class.");
+        return;
+    }
+
+    // `this` is never assignable.
+    named_variable(compiler, "this".to_string(), false);
 }
 
-fn parse_string(compiler: &mut Compiler) {
+fn parse_super(compiler: &mut Compiler, _can_assign: bool) {
+    match compiler.classes.last() {
+        None => emit_error(compiler, "Can't use 'super' outside of a class."),
+        Some(class) if !class.has_superclass => {
+            emit_error(compiler, "Can't use 'super' in a class with no superclass.");
+        },
+        Some(_) => {},
+    }
+
+    consume(compiler, TokenType::Dot, "Expect '.' after 'super'.");
+    consume(compiler, TokenType::Identifier, "Expect superclass method name.");
+    let name = compiler.parser.previous.content.clone();
+    let name_idx = make_str(compiler, name);
+
+    named_variable(compiler, "this".to_string(), false);
+    if try_consume(compiler, TokenType::LeftParen) {
+        let argc = parse_arguments(compiler);
+        named_variable(compiler, "super".to_string(), false);
+        compiler.emit_inst(Inst::OP_SUPER_INVOKE { name_idx, argc });
+    } else {
+        named_variable(compiler, "super".to_string(), false);
+        compiler.emit_inst(Inst::OP_GET_SUPER { name_idx });
+    }
+}
+
+fn named_variable(compiler: &mut Compiler, vname: String, can_assign: bool) {
+    let level = compiler.states.len() - 1;
+    let (get_inst, set_inst) = if let Some(slot) = resolve_local(compiler, level, &vname) {
+        (Inst::OP_GET_LOCAL { slot }, Inst::OP_SET_LOCAL { slot })
+    } else if let Some(idx) = resolve_upvalue(compiler, level, &vname) {
+        (Inst::OP_GET_UPVALUE { idx }, Inst::OP_SET_UPVALUE { idx })
+    } else {
+        let slot = resolve_global(compiler, vname);
+        (Inst::OP_GET_GLOBAL { slot }, Inst::OP_SET_GLOBAL { slot })
+    };
+
+    if can_assign && try_consume(compiler, TokenType::Equal) {
+        parse_expression(compiler);
+        compiler.emit_inst(set_inst);
+    } else {
+        compiler.emit_inst(get_inst);
+    }
+}
+
+fn parse_string(compiler: &mut Compiler, _can_assign: bool) {
     let s: String;
     {
         let s0 = &compiler.parser.previous.content;
@@ -241,7 +708,7 @@ fn parse_string(compiler: &mut Compiler) {
     emit_str(compiler, s);
 }
 
-fn parse_literal(compiler: &mut Compiler) {
+fn parse_literal(compiler: &mut Compiler, _can_assign: bool) {
     let tp = compiler.parser.previous.tp;
 
     match tp {
@@ -252,15 +719,15 @@ fn parse_literal(compiler: &mut Compiler) {
     }
 }
 
-fn parse_grouping(compiler: &mut Compiler) {
+fn parse_grouping(compiler: &mut Compiler, _can_assign: bool) {
     parse_expression(compiler);
-    consume(compiler, TokenType::RightParen, "Expecting ')' after expression.".into());
+    consume(compiler, TokenType::RightParen, "Expecting ')' after expression.");
 }
 
-fn parse_unary(compiler: &mut Compiler) {
+fn parse_unary(compiler: &mut Compiler, _can_assign: bool) {
     let tp = compiler.parser.previous.tp;
 
-    parse_expression(compiler);
+    parse_prec(compiler, Precedence::Unary);
 
     match tp {
         TokenType::Minus => {
@@ -269,13 +736,11 @@ fn parse_unary(compiler: &mut Compiler) {
         TokenType::Bang => {
             compiler.emit_inst(Inst::OP_NOT);
         },
-        _ => {
-            panic!("Unexpected unary operator: {:?}", tp);
-        }
+        _ => emit_error(compiler, "Unexpected unary operator."),
     }
 }
 
-fn parse_binary(compiler: &mut Compiler) {
+fn parse_binary(compiler: &mut Compiler, _can_assign: bool) {
     let op_type = compiler.parser.previous.tp;
     let prec = compiler.parser.get_rule(op_type).prec;
 
@@ -297,6 +762,16 @@ fn parse_binary(compiler: &mut Compiler) {
         TokenType::EqualEqual => {
             compiler.emit_inst(Inst::OP_EQ);
         },
+        TokenType::BangEqual => {
+            compiler.emit_inst(Inst::OP_EQ);
+            compiler.emit_inst(Inst::OP_NOT);
+        },
+        TokenType::GreaterEqual => {
+            compiler.emit_inst(Inst::OP_GE);
+        },
+        TokenType::LessEqual => {
+            compiler.emit_inst(Inst::OP_LE);
+        },
         TokenType::Greater => {
             compiler.emit_inst(Inst::OP_GT);
         },
@@ -307,6 +782,75 @@ fn parse_binary(compiler: &mut Compiler) {
     }
 }
 
+fn parse_call(compiler: &mut Compiler, _can_assign: bool) {
+    let argc = parse_arguments(compiler);
+    compiler.emit_inst(Inst::OP_CALL { argc });
+}
+
+fn parse_arguments(compiler: &mut Compiler) -> usize {
+    let mut argc = 0;
+
+    if !check_next(compiler, TokenType::RightParen) {
+        loop {
+            parse_expression(compiler);
+            if argc == ARGS_MAX {
+                emit_error(compiler, "Can't have more than 255 arguments.");
+            }
+            argc += 1;
+
+            if !try_consume(compiler, TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    consume(compiler, TokenType::RightParen, "Expect ')' after arguments.");
+
+    // Past the limit an error has been reported, but the operand must still
+    // fit its encoding.
+    argc.min(ARGS_MAX)
+}
+
+fn parse_dot(compiler: &mut Compiler, can_assign: bool) {
+    consume(compiler, TokenType::Identifier, "Expect property name after '.'.");
+    let name = compiler.parser.previous.content.clone();
+    let name_idx = make_str(compiler, name);
+
+    if can_assign && try_consume(compiler, TokenType::Equal) {
+        parse_expression(compiler);
+        compiler.emit_inst(Inst::OP_SET_PROPERTY { name_idx });
+    } else if try_consume(compiler, TokenType::LeftParen) {
+        // Calling a method right away saves creating a bound method.
+        let argc = parse_arguments(compiler);
+        compiler.emit_inst(Inst::OP_INVOKE { name_idx, argc });
+    } else {
+        compiler.emit_inst(Inst::OP_GET_PROPERTY { name_idx });
+    }
+}
+
+fn parse_and(compiler: &mut Compiler, _can_assign: bool) {
+    // The left operand is on the stack. If it is falsey it is the result, and
+    // the right operand is skipped.
+    let end_jump = emit_jump(compiler, Inst::OP_JUMP_IF_FALSE { offset: 0 });
+
+    compiler.emit_inst(Inst::OP_POP);
+    parse_prec(compiler, Precedence::And);
+
+    patch_jump(compiler, end_jump);
+}
+
+fn parse_or(compiler: &mut Compiler, _can_assign: bool) {
+    // A truthy left operand is the result, so only a falsey one falls through
+    // to the right operand.
+    let else_jump = emit_jump(compiler, Inst::OP_JUMP_IF_FALSE { offset: 0 });
+    let end_jump = emit_jump(compiler, Inst::OP_JUMP { offset: 0 });
+
+    patch_jump(compiler, else_jump);
+    compiler.emit_inst(Inst::OP_POP);
+    parse_prec(compiler, Precedence::Or);
+
+    patch_jump(compiler, end_jump);
+}
+
 #[derive(Debug)]
 #[derive(Clone, Copy)]
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -342,57 +886,73 @@ impl Precedence {
     }
 }
 
-pub type ParseFn = fn(&mut Compiler) -> ();
+/// A prefix or infix parsing function. The flag tells whether the expression
+/// being parsed may be followed by `=`, i.e. whether it sits at assignment
+/// precedence.
+pub type ParseFn = fn(&mut Compiler, bool) -> ();
 
+#[derive(Clone, Copy)]
 pub struct ParseRule {
     prefix: Option<ParseFn>,
     infix: Option<ParseFn>,
     prec: Precedence,
 }
 
-pub type ParseTable = HashMap<TokenType, ParseRule>;
-
 impl ParseRule {
     pub fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, prec: Precedence) -> ParseRule {
         ParseRule { prefix, infix, prec }
     }
 
-    pub fn make_rules() -> ParseTable {
-        let mut m = HashMap::new();
-
-        m.insert(TokenType::LeftParen, ParseRule::new(Some(parse_grouping), None, Precedence::None));
-        m.insert(TokenType::RightParen, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::LeftBrace, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::RightBrace, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::Comma, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::Dot, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::SemiColon, ParseRule::new(None, None, Precedence::None));
-        m.insert(TokenType::Print, ParseRule::new(None, None, Precedence::None));
-
-        m.insert(TokenType::Minus, ParseRule::new(Some(parse_unary), Some(parse_binary), Precedence::Term));
-        m.insert(TokenType::Plus, ParseRule::new(None, Some(parse_binary), Precedence::Term));
-        m.insert(TokenType::Slash, ParseRule::new(None, Some(parse_binary), Precedence::Factor));
-        m.insert(TokenType::Star, ParseRule::new(None, Some(parse_binary), Precedence::Factor));
-
-        m.insert(TokenType::EqualEqual, ParseRule::new(None, Some(parse_binary), Precedence::Equality));
-        m.insert(TokenType::Greater, ParseRule::new(None, Some(parse_binary), Precedence::Comparison));
-        m.insert(TokenType::Less, ParseRule::new(None, Some(parse_binary), Precedence::Comparison));
-
-        m.insert(TokenType::Number, ParseRule::new(Some(parse_number), None, Precedence::None));
-        m.insert(TokenType::Identifier, ParseRule::new(Some(parse_variable), None, Precedence::None));
-        m.insert(TokenType::String, ParseRule::new(Some(parse_string), None, Precedence::None));
-        m.insert(TokenType::False, ParseRule::new(Some(parse_literal), None, Precedence::None));
-        m.insert(TokenType::True, ParseRule::new(Some(parse_literal), None, Precedence::None));
-        m.insert(TokenType::Nil, ParseRule::new(Some(parse_literal), None, Precedence::None));
-
-        m.insert(TokenType::Bang, ParseRule::new(Some(parse_unary), None, Precedence::None));
-
-
-        m.insert(TokenType::EOF, ParseRule::new(None, None, Precedence::None));
-
-        // TODO finish parsing table
-
-        m
+    /// The rule for every token type. The match is deliberately exhaustive so
+    /// that adding a token without deciding how it parses fails to compile.
+    pub fn for_token(tp: TokenType) -> ParseRule {
+        match tp {
+            TokenType::LeftParen => ParseRule::new(Some(parse_grouping), Some(parse_call), Precedence::Call),
+            TokenType::RightParen => ParseRule::new(None, None, Precedence::None),
+            TokenType::LeftBrace => ParseRule::new(None, None, Precedence::None),
+            TokenType::RightBrace => ParseRule::new(None, None, Precedence::None),
+            TokenType::Comma => ParseRule::new(None, None, Precedence::None),
+            TokenType::Dot => ParseRule::new(None, Some(parse_dot), Precedence::Call),
+            TokenType::SemiColon => ParseRule::new(None, None, Precedence::None),
+
+            TokenType::Minus => ParseRule::new(Some(parse_unary), Some(parse_binary), Precedence::Term),
+            TokenType::Plus => ParseRule::new(None, Some(parse_binary), Precedence::Term),
+            TokenType::Slash => ParseRule::new(None, Some(parse_binary), Precedence::Factor),
+            TokenType::Star => ParseRule::new(None, Some(parse_binary), Precedence::Factor),
+
+            TokenType::Bang => ParseRule::new(Some(parse_unary), None, Precedence::None),
+            TokenType::BangEqual => ParseRule::new(None, Some(parse_binary), Precedence::Equality),
+            TokenType::Equal => ParseRule::new(None, None, Precedence::None),
+            TokenType::EqualEqual => ParseRule::new(None, Some(parse_binary), Precedence::Equality),
+            TokenType::Greater => ParseRule::new(None, Some(parse_binary), Precedence::Comparison),
+            TokenType::GreaterEqual => ParseRule::new(None, Some(parse_binary), Precedence::Comparison),
+            TokenType::Less => ParseRule::new(None, Some(parse_binary), Precedence::Comparison),
+            TokenType::LessEqual => ParseRule::new(None, Some(parse_binary), Precedence::Comparison),
+
+            TokenType::Identifier => ParseRule::new(Some(parse_variable), None, Precedence::None),
+            TokenType::String => ParseRule::new(Some(parse_string), None, Precedence::None),
+            TokenType::Number => ParseRule::new(Some(parse_number), None, Precedence::None),
+
+            TokenType::And => ParseRule::new(None, Some(parse_and), Precedence::And),
+            TokenType::Class => ParseRule::new(None, None, Precedence::None),
+            TokenType::Else => ParseRule::new(None, None, Precedence::None),
+            TokenType::False => ParseRule::new(Some(parse_literal), None, Precedence::None),
+            TokenType::For => ParseRule::new(None, None, Precedence::None),
+            TokenType::Fun => ParseRule::new(None, None, Precedence::None),
+            TokenType::If => ParseRule::new(None, None, Precedence::None),
+            TokenType::Nil => ParseRule::new(Some(parse_literal), None, Precedence::None),
+            TokenType::Or => ParseRule::new(None, Some(parse_or), Precedence::Or),
+            TokenType::Print => ParseRule::new(None, None, Precedence::None),
+            TokenType::Return => ParseRule::new(None, None, Precedence::None),
+            TokenType::Super => ParseRule::new(Some(parse_super), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(parse_this), None, Precedence::None),
+            TokenType::True => ParseRule::new(Some(parse_literal), None, Precedence::None),
+            TokenType::Var => ParseRule::new(None, None, Precedence::None),
+            TokenType::While => ParseRule::new(None, None, Precedence::None),
+
+            TokenType::Error => ParseRule::new(None, None, Precedence::None),
+            TokenType::EOF => ParseRule::new(None, None, Precedence::None),
+        }
     }
 }
 
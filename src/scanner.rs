@@ -40,7 +40,7 @@ impl Token {
             TokenType::Error => format!("<error: {}>", self.content),
             TokenType::EOF => "<eof>".into(),
             TokenType::Identifier => format!("@{}", self.content),
-            _ => self.content.clone().into(),
+            _ => self.content.clone(),
         }
     }
 }
@@ -52,6 +52,12 @@ pub struct ScannerState {
     pub line: u32
 }
 
+impl Default for ScannerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ScannerState {
     pub fn new() -> ScannerState {
         ScannerState { start: 0, current: 0, line: 1 }
@@ -82,9 +88,10 @@ fn error_token(compiler: &Compiler, msg: String) -> Token {
     }
 }
 
+/// The next character, or `'\0'` at the end of the source.
 fn peek(compiler: &Compiler) -> char {
-    let pos = compiler.scanner.current;
-    compiler.source.as_bytes()[pos as usize] as char
+    let pos = compiler.scanner.current as usize;
+    compiler.source.as_bytes().get(pos).map_or('\0', |&b| b as char)
 }
 
 fn peek_next(compiler: &Compiler) -> Option<char> {
@@ -96,9 +103,11 @@ fn peek_next(compiler: &Compiler) -> Option<char> {
     }
 }
 
+/// Consume the next character. Returns `'\0'` without moving at the end of
+/// the source.
 fn advance(compiler: &mut Compiler) -> char {
     if is_eof(compiler) {
-        panic!("EOF!")
+        '\0'
     } else {
         compiler.scanner.current += 1;
         compiler.source.as_bytes()[(compiler.scanner.current - 1) as usize] as char
@@ -150,7 +159,14 @@ pub fn next_token(compiler: &mut Compiler) -> Token {
         _ => (),
     }
 
-    error_token(compiler, format!("Fail to tokenize at character '{}'", c))
+    // Skip the rest of a multi-byte character, so that later tokens start on
+    // a character boundary.
+    while !is_eof(compiler) && !compiler.source.is_char_boundary(compiler.scanner.current as usize) {
+        advance(compiler);
+    }
+    let start = compiler.scanner.start as usize;
+    let end = compiler.scanner.current as usize;
+    error_token(compiler, format!("Fail to tokenize at character '{}'", &compiler.source[start..end]))
 }
 
 fn skip_whitespaces(compiler: &mut Compiler) {
@@ -199,7 +215,7 @@ fn scan_string(compiler: &mut Compiler) -> Token {
 }
 
 fn is_digit(ch: char) -> bool {
-    ch >= '0' && ch <= '9'
+    ch.is_ascii_digit()
 }
 
 fn scan_number(compiler: &mut Compiler) -> Token {
@@ -207,7 +223,9 @@ fn scan_number(compiler: &mut Compiler) -> Token {
         advance(compiler);
     }
 
-    if peek(compiler) == '.' {  // start scaning fraction part
+    // A fraction part needs at least one digit after the dot, so that `1.foo`
+    // stays a property access.
+    if peek(compiler) == '.' && peek_next(compiler).is_some_and(is_digit) {
         advance(compiler);
 
         while !is_eof(compiler) && is_digit(peek(compiler)) {
@@ -219,7 +237,7 @@ fn scan_number(compiler: &mut Compiler) -> Token {
 }
 
 fn is_alpha(ch: char) -> bool {
-    (ch >= 'a' && ch <= 'z') || (ch >= 'A' && ch <= 'Z')
+    ch.is_ascii_alphabetic()
 }
 
 fn is_alpha_underscore(ch: char) -> bool {
@@ -249,7 +267,7 @@ fn keyword_or_identifier(content: &str) -> TokenType {
 }
 
 fn scan_identifier(compiler: &mut Compiler) -> Token {
-    while !is_eof(compiler) && is_alpha_underscore(peek(compiler)) {
+    while !is_eof(compiler) && (is_alpha_underscore(peek(compiler)) || is_digit(peek(compiler))) {
         advance(compiler);
     }
 
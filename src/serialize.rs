@@ -0,0 +1,387 @@
+//! The `.loxc` format of compiled scripts.
+//!
+//! A file starts with a header: the magic bytes `\x7fLOX`, which no Lox source
+//! can start with, the format version as a little-endian `u16`, and the FNV-1a
+//! checksum of the rest of the file as a little-endian `u32`. The payload that
+//! follows holds the names of the global slots of the compiling VM, then the
+//! script function. A function is its name, arity, upvalue descriptors and
+//! chunk; constants that are functions nest recursively. Integers are
+//! little-endian `u32`s unless stated otherwise, and strings are a length
+//! followed by UTF-8 bytes.
+//!
+//! Global slots are only meaningful within the VM that compiled the code, so
+//! the loader resolves the names again and relocates the code when slots
+//! differ. Loaded code then goes through the verifier.
+
+use crate::chunk::{Chunk, Inst, LineRun, JUMP_LEN, SHORT_MAX};
+use crate::globals::Globals;
+use crate::heap::{Heap, ObjRef};
+use crate::obj::{Function, Obj, UpvalueDesc};
+use crate::value::Value;
+use crate::verify;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+pub const MAGIC: &[u8; 4] = b"\x7fLOX";
+/// Bumped on every incompatible change of the format.
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 10;
+/// Maximum nesting of function prototypes, which keeps loading from
+/// overflowing the native stack.
+const NESTING_MAX: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Why a compiled script could not be loaded.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub message: String,
+}
+
+impl LoadError {
+    fn new(message: &str) -> LoadError {
+        LoadError { message: message.to_string() }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid compiled script: {}", self.message)
+    }
+}
+
+/// FNV-1a hash of the payload, to detect corrupted files.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(2166136261u32, |hash, &b| (hash ^ b as u32).wrapping_mul(16777619))
+}
+
+/// Whether `bytes` look like a compiled script rather than source code.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encode a compiled script. Its constants are looked up in `heap`, and its
+/// global slots in `globals`.
+pub fn serialize(heap: &Heap, globals: &Globals, function: &Function) -> Vec<u8> {
+    let mut payload = Vec::new();
+
+    write_u32(&mut payload, globals.len());
+    for slot in 0..globals.len() {
+        let name = globals.name(slot).map_or("", |name| heap.get(name).as_str().unwrap_or(""));
+        write_str(&mut payload, name);
+    }
+    write_function(&mut payload, heap, function);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("Length does not fit in the format");
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, heap: &Heap, function: &Function) {
+    match &function.name {
+        Some(name) => {
+            out.push(1);
+            write_str(out, name);
+        },
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+
+    write_u32(out, function.upvalues.len());
+    for upvalue in &function.upvalues {
+        out.push(upvalue.is_local as u8);
+        write_u32(out, upvalue.index);
+    }
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+
+    write_u32(out, chunk.lines.len());
+    for run in &chunk.lines {
+        write_u32(out, run.offset);
+        write_u32(out, run.line);
+    }
+
+    write_u32(out, chunk.value_array.data.len());
+    for constant in &chunk.value_array.data {
+        write_constant(out, heap, constant);
+    }
+}
+
+fn write_constant(out: &mut Vec<u8>, heap: &Heap, constant: &Value) {
+    match constant {
+        Value::NIL => out.push(TAG_NIL),
+        Value::BOOL { data: false } => out.push(TAG_FALSE),
+        Value::BOOL { data: true } => out.push(TAG_TRUE),
+        Value::DOUBLE { data } => {
+            out.push(TAG_NUMBER);
+            out.extend_from_slice(&data.to_le_bytes());
+        },
+        Value::OBJ { data } => match heap.get(*data) {
+            Obj::Str { data, .. } => {
+                out.push(TAG_STRING);
+                write_str(out, data);
+            },
+            Obj::Function { data } => {
+                out.push(TAG_FUNCTION);
+                write_function(out, heap, data);
+            },
+            obj => panic!("Constant cannot be serialized: {:?}", obj),
+        },
+        Value::EMPTY => panic!("Constant cannot be serialized: EMPTY"),
+    }
+}
+
+/// Decode a compiled script, allocating its constants on `heap` and resolving
+/// its globals in `globals`. Like compilation, loading never collects.
+pub fn deserialize(bytes: &[u8], heap: &mut Heap, globals: &mut Globals) -> Result<Function, LoadError> {
+    if bytes.len() < HEADER_LEN || !is_compiled(bytes) {
+        return Err(LoadError::new("missing header."));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(LoadError { message: format!("unsupported format version {}.", version) });
+    }
+
+    let payload = &bytes[HEADER_LEN..];
+    if u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) != checksum(payload) {
+        return Err(LoadError::new("checksum mismatch."));
+    }
+
+    let mut reader = Reader { bytes: payload, pos: 0, heap, depth: 0 };
+
+    let mut slots = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let name = reader.heap.intern(name);
+        let slot = globals.resolve(name);
+        if slot > SHORT_MAX {
+            return Err(LoadError::new("too many global variables."));
+        }
+        slots.push(slot);
+    }
+
+    let mut function = reader.function()?;
+    if reader.pos != payload.len() {
+        return Err(LoadError::new("trailing bytes after the script."));
+    }
+
+    // Functions nested in the constants already reside on the heap, so they
+    // are relocated in place.
+    if slots.iter().enumerate().any(|(idx, &slot)| idx != slot) {
+        relocate_chunk(&mut function.chunk, heap, &slots)?;
+    }
+
+    // Nothing vouches for the code of a file, so it is checked before it runs.
+    verify::verify(heap, globals, &function).map_err(|err| LoadError { message: err.to_string() })?;
+    Ok(function)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    heap: &'a mut Heap,
+    /// Current nesting of function prototypes.
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoadError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| LoadError::new("unexpected end of file."))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let bytes = self.take(8)?;
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| LoadError::new("string is not valid UTF-8."))
+    }
+
+    fn function(&mut self) -> Result<Function, LoadError> {
+        if self.depth >= NESTING_MAX {
+            return Err(LoadError::new("functions are nested too deeply."));
+        }
+        self.depth += 1;
+
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(LoadError::new("invalid function name.")),
+        };
+        let mut function = Function::new(name);
+        function.arity = self.u32()?;
+
+        for _ in 0..self.u32()? {
+            let is_local = match self.u8()? {
+                0 => false,
+                1 => true,
+                _ => return Err(LoadError::new("invalid upvalue descriptor.")),
+            };
+            let index = self.u32()?;
+            function.upvalues.push(UpvalueDesc { is_local, index });
+        }
+
+        let len = self.u32()?;
+        function.chunk.code = self.take(len)?.to_vec();
+
+        for _ in 0..self.u32()? {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            function.chunk.lines.push(LineRun { offset, line });
+        }
+
+        let count = self.u32()?;
+        for _ in 0..count {
+            let constant = self.constant()?;
+            if function.chunk.value_array.add_constant(constant).is_none() {
+                return Err(LoadError::new("too many constants in one chunk."));
+            }
+        }
+        // Deduplication would shift the indexes the code refers to.
+        if function.chunk.value_array.data.len() != count {
+            return Err(LoadError::new("duplicate constants."));
+        }
+
+        self.depth -= 1;
+        Ok(function)
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let constant = match self.u8()? {
+            TAG_NIL => Value::NIL,
+            TAG_FALSE => Value::BOOL { data: false },
+            TAG_TRUE => Value::BOOL { data: true },
+            TAG_NUMBER => Value::DOUBLE { data: self.f64()? },
+            TAG_STRING => {
+                let s = self.string()?;
+                Value::OBJ { data: self.heap.intern(s) }
+            },
+            TAG_FUNCTION => {
+                let function = self.function()?;
+                Value::OBJ { data: self.heap.alloc(Obj::Function { data: function }) }
+            },
+            tag => return Err(LoadError { message: format!("unknown constant tag {}.", tag) }),
+        };
+        Ok(constant)
+    }
+}
+
+/// Rewrite the global slots of `chunk` and of the functions among its
+/// constants, from the slots of the compiling VM to `slots`.
+fn relocate_chunk(chunk: &mut Chunk, heap: &mut Heap, slots: &[usize]) -> Result<(), LoadError> {
+    relocate_globals(chunk, slots)?;
+
+    let nested: Vec<ObjRef> = chunk.value_array.data.iter().filter_map(Value::as_obj).collect();
+    for r in nested {
+        let mut nested_chunk = match heap.get_mut(r) {
+            Obj::Function { data } => mem::take(&mut data.chunk),
+            _ => continue,
+        };
+        relocate_chunk(&mut nested_chunk, heap, slots)?;
+        if let Obj::Function { data } = heap.get_mut(r) {
+            data.chunk = nested_chunk;
+        }
+    }
+    Ok(())
+}
+
+/// Re-encode the code of `chunk` with every global slot mapped through
+/// `slots`. Operands may change width, so jump offsets are recomputed.
+fn relocate_globals(chunk: &mut Chunk, slots: &[usize]) -> Result<(), LoadError> {
+    let malformed = || LoadError::new("malformed bytecode.");
+    let remap = |slot: usize| slots.get(slot).copied().ok_or_else(|| LoadError::new("unknown global slot."));
+
+    // Decode everything first, remembering where each instruction started.
+    let mut insts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (inst, len) = chunk.decode(offset).ok_or_else(malformed)?;
+        let inst = match inst {
+            Inst::OP_DEFINE_GLOBAL { slot } => Inst::OP_DEFINE_GLOBAL { slot: remap(slot)? },
+            Inst::OP_GET_GLOBAL { slot } => Inst::OP_GET_GLOBAL { slot: remap(slot)? },
+            Inst::OP_SET_GLOBAL { slot } => Inst::OP_SET_GLOBAL { slot: remap(slot)? },
+            inst => inst,
+        };
+        insts.push((offset, inst));
+        offset += len;
+    }
+
+    // Jumps keep their length, so new offsets only depend on the slots.
+    let mut new_offsets = HashMap::new();
+    let mut sizing = Chunk::new();
+    for &(old, inst) in &insts {
+        new_offsets.insert(old, sizing.code.len());
+        sizing.write(inst, 0);
+    }
+    new_offsets.insert(chunk.code.len(), sizing.code.len());
+
+    let mut relocated = Chunk::new();
+    for &(old, inst) in &insts {
+        let new_next = relocated.code.len() + JUMP_LEN;
+        let old_next = old + JUMP_LEN;
+        let target = |old_target: Option<usize>| {
+            old_target.and_then(|old_target| new_offsets.get(&old_target).copied()).ok_or_else(malformed)
+        };
+        let fits = |offset: Option<usize>| offset.filter(|&offset| offset <= SHORT_MAX).ok_or_else(malformed);
+
+        let inst = match inst {
+            Inst::OP_JUMP { offset } => {
+                Inst::OP_JUMP { offset: fits(target(old_next.checked_add(offset))?.checked_sub(new_next))? }
+            },
+            Inst::OP_JUMP_IF_FALSE { offset } => {
+                let offset = fits(target(old_next.checked_add(offset))?.checked_sub(new_next))?;
+                Inst::OP_JUMP_IF_FALSE { offset }
+            },
+            Inst::OP_LOOP { offset } => {
+                Inst::OP_LOOP { offset: fits(new_next.checked_sub(target(old_next.checked_sub(offset))?))? }
+            },
+            inst => inst,
+        };
+        relocated.write(inst, chunk.line_at(old));
+    }
+
+    chunk.code = relocated.code;
+    chunk.lines = relocated.lines;
+    Ok(())
+}
@@ -1,8 +1,8 @@
 #[derive(Debug)]
 #[derive(Clone, Copy)]
 pub struct Span {
-    start: usize,
-    len: usize,
+    pub start: usize,
+    pub len: usize,
 }
 
 impl Span {
@@ -1,6 +1,10 @@
-use std::any::{Any, TypeId};
-use crate::obj::Obj;
-use std::rc::Rc;
+use crate::heap::ObjRef;
+
+use std::collections::HashMap;
+
+/// Maximum number of distinct constants in a single chunk, so that their
+/// indexes fit the two-byte operands of `_LONG` instructions.
+pub const CONSTANTS_MAX: usize = 1 << 16;
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -8,7 +12,7 @@ pub enum Value {
     DOUBLE { data: f64 },
     BOOL { data: bool },
     NIL,
-    OBJ { data: Rc<Obj> },
+    OBJ { data: ObjRef },
     EMPTY,
 }
 
@@ -23,39 +27,75 @@ impl Value {
         }
     }
 
-    pub fn is_string(&self) -> bool {
-        match self {
-            Value::OBJ { data } => match data.as_ref() {
-                Obj::Str { data: _ } => true
-            },
-            _ => false
-        }
+    /// `nil` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::NIL | Value::BOOL { data: false })
     }
 
-    pub fn as_string(&self) -> Option<&str> {
+    pub fn as_obj(&self) -> Option<ObjRef> {
         match self {
-            Value::OBJ { data } => match data.as_ref() {
-                Obj::Str { data: s } => Option::Some(s.as_ref())
-            },
+            Value::OBJ { data } => Option::Some(*data),
             _ => Option::None
         }
     }
+}
 
-    pub fn create_string_obj(s: String) -> Value {
-        let obj = Obj::Str { data: s };
-        Value::OBJ { data: Rc::new(obj) }
+/// Identity of a constant for deduplication. Numbers compare by bits, so
+/// that `0` and `-0` stay distinct; strings are interned, so comparing
+/// objects by reference also merges equal strings.
+#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Double(u64),
+    Bool(bool),
+    Nil,
+    Obj(ObjRef),
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<ConstantKey> {
+        match value {
+            Value::DOUBLE { data } => Some(ConstantKey::Double(data.to_bits())),
+            Value::BOOL { data } => Some(ConstantKey::Bool(*data)),
+            Value::NIL => Some(ConstantKey::Nil),
+            Value::OBJ { data } => Some(ConstantKey::Obj(*data)),
+            Value::EMPTY => None,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct ValueArray {
     pub data: Vec<Value>,
+    /// Index of every constant in `data`, so that equal constants are stored
+    /// once.
+    indices: HashMap<ConstantKey, usize>,
+}
+
+impl Default for ValueArray {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ValueArray {
-    pub fn add_constant(&mut self, value: Value) -> usize {
+    /// The index of a constant equal to `value`, added if there is none yet.
+    /// Returns `None` when the array already holds `CONSTANTS_MAX` constants.
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
+        let key = ConstantKey::of(&value);
+        if let Some(idx) = key.and_then(|key| self.indices.get(&key)) {
+            return Some(*idx);
+        }
+
+        if self.data.len() >= CONSTANTS_MAX {
+            return None;
+        }
+
         self.data.push(value);
-        self.data.len() - 1
+        if let Some(key) = key {
+            self.indices.insert(key, self.data.len() - 1);
+        }
+        Some(self.data.len() - 1)
     }
 
     pub fn read(&self, idx: usize) -> Value {
@@ -64,8 +104,8 @@ impl ValueArray {
 
     pub fn new() -> ValueArray {
         ValueArray {
-            data: Vec::new()
+            data: Vec::new(),
+            indices: HashMap::new(),
         }
     }
 }
-
@@ -0,0 +1,303 @@
+//! Static checks of bytecode, run before executing code that did not come
+//! straight from the compiler.
+//!
+//! A chunk is accepted when every byte belongs to a well-formed instruction,
+//! every operand refers to something that exists, every jump lands on an
+//! instruction, it ends in `RETURN`, and the stack height at each instruction
+//! is the same over all the paths reaching it, never dropping into slot zero.
+//! The functions among the constants are checked the same way.
+
+use crate::chunk::Inst;
+use crate::globals::Globals;
+use crate::heap::{Heap, ObjRef};
+use crate::obj::Function;
+use crate::value::Value;
+use crate::debug::show_function;
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// Why a function was rejected.
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub message: String,
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    /// Offset of the offending instruction in the chunk of `function`.
+    pub offset: usize,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "{} (at offset {} in {}())", self.message, self.offset, name),
+            None => write!(f, "{} (at offset {} in script)", self.message, self.offset),
+        }
+    }
+}
+
+/// Check `function` and every function nested in its constants, against the
+/// objects of `heap` and the global slots of `globals`. `function` is run as
+/// the script, so it may take neither arguments nor upvalues.
+pub fn verify(heap: &Heap, globals: &Globals, function: &Function) -> Result<(), VerifyError> {
+    let error = |message: String| VerifyError { message, function: function.name.clone(), offset: 0 };
+    if function.arity != 0 {
+        return Err(error(format!("The script takes {} arguments, but is run with none.", function.arity)));
+    }
+    if !function.upvalues.is_empty() {
+        return Err(error(format!("The script captures {} upvalues, but is run without any.", function.upvalues.len())));
+    }
+
+    let mut verified = HashSet::new();
+    verify_function(heap, globals, function, &mut verified)
+}
+
+fn verify_function(heap: &Heap, globals: &Globals, function: &Function, verified: &mut HashSet<ObjRef>)
+    -> Result<(), VerifyError>
+{
+    let verifier = Verifier { heap, globals, function };
+    verifier.check()?;
+
+    for constant in &function.chunk.value_array.data {
+        if let Some(r) = constant.as_obj() {
+            if let Some(nested) = heap.get(r).as_function() {
+                if verified.insert(r) {
+                    verify_function(heap, globals, nested, verified)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    heap: &'a Heap,
+    globals: &'a Globals,
+    function: &'a Function,
+}
+
+/// Net effect of an instruction on the stack.
+struct Effect {
+    pops: usize,
+    pushes: usize,
+}
+
+impl Effect {
+    fn new(pops: usize, pushes: usize) -> Effect {
+        Effect { pops, pushes }
+    }
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, message: String) -> VerifyError {
+        VerifyError { message, function: self.function.name.clone(), offset }
+    }
+
+    fn check(&self) -> Result<(), VerifyError> {
+        let insts = self.decode()?;
+
+        match insts.last() {
+            Some(&(_, Inst::RETURN, _)) => {},
+            Some(&(offset, _, _)) => return Err(self.error(offset, "Chunk does not end in RETURN.".to_string())),
+            None => return Err(self.error(0, "Chunk is empty.".to_string())),
+        }
+
+        // Index of the instruction starting at each offset.
+        let mut starts = vec![None; self.function.chunk.code.len()];
+        for (idx, &(offset, _, _)) in insts.iter().enumerate() {
+            starts[offset] = Some(idx);
+        }
+
+        for &(offset, inst, _) in &insts {
+            self.check_operands(offset, inst)?;
+        }
+
+        self.check_stack(&insts, &starts)
+    }
+
+    /// Decode the whole chunk into instructions with their offsets and lengths.
+    fn decode(&self) -> Result<Vec<(usize, Inst, usize)>, VerifyError> {
+        let chunk = &self.function.chunk;
+        let mut insts = Vec::new();
+        let mut offset = 0;
+
+        while offset < chunk.code.len() {
+            match chunk.decode(offset) {
+                Some((inst, len)) => {
+                    insts.push((offset, inst, len));
+                    offset += len;
+                },
+                None => {
+                    let message = format!("Invalid or truncated instruction with opcode {}.", chunk.code[offset]);
+                    return Err(self.error(offset, message));
+                },
+            }
+        }
+        Ok(insts)
+    }
+
+    fn constant(&self, offset: usize, idx: usize) -> Result<&Value, VerifyError> {
+        let constants = &self.function.chunk.value_array.data;
+        constants.get(idx).ok_or_else(|| {
+            self.error(offset, format!("Constant index {} out of range, the chunk has {} constants.", idx, constants.len()))
+        })
+    }
+
+    fn check_name(&self, offset: usize, name_idx: usize) -> Result<(), VerifyError> {
+        if self.heap.is_string(self.constant(offset, name_idx)?) {
+            Ok(())
+        } else {
+            Err(self.error(offset, format!("Constant {} is not a string, as names must be.", name_idx)))
+        }
+    }
+
+    fn check_operands(&self, offset: usize, inst: Inst) -> Result<(), VerifyError> {
+        match inst {
+            Inst::CONSTANT { idx } => {
+                self.constant(offset, idx)?;
+            },
+            Inst::OP_DEFINE_GLOBAL { slot } | Inst::OP_GET_GLOBAL { slot } | Inst::OP_SET_GLOBAL { slot }
+                if slot >= self.globals.len() =>
+            {
+                let message = format!("Global slot {} out of range, the VM has {} globals.", slot, self.globals.len());
+                return Err(self.error(offset, message));
+            },
+            Inst::OP_GET_UPVALUE { idx } | Inst::OP_SET_UPVALUE { idx } if idx >= self.function.upvalues.len() => {
+                let message = format!("Upvalue {} out of range, the function has {}.", idx, self.function.upvalues.len());
+                return Err(self.error(offset, message));
+            },
+            Inst::OP_CLOSURE { idx } => {
+                let nested = self.closure_function(offset, idx)?;
+                for upvalue in nested.upvalues.iter().filter(|upvalue| !upvalue.is_local) {
+                    if upvalue.index >= self.function.upvalues.len() {
+                        let message = format!(
+                            "{} captures upvalue {}, but the enclosing function has {}.",
+                            show_function(nested), upvalue.index, self.function.upvalues.len()
+                        );
+                        return Err(self.error(offset, message));
+                    }
+                }
+            },
+            Inst::OP_CLASS { name_idx }
+            | Inst::OP_GET_PROPERTY { name_idx }
+            | Inst::OP_SET_PROPERTY { name_idx }
+            | Inst::OP_METHOD { name_idx }
+            | Inst::OP_INVOKE { name_idx, .. }
+            | Inst::OP_GET_SUPER { name_idx }
+            | Inst::OP_SUPER_INVOKE { name_idx, .. } => self.check_name(offset, name_idx)?,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn closure_function(&self, offset: usize, idx: usize) -> Result<&Function, VerifyError> {
+        let function = self.constant(offset, idx)?.as_obj().and_then(|r| self.heap.get(r).as_function());
+        function.ok_or_else(|| self.error(offset, format!("Constant {} is not a function.", idx)))
+    }
+
+    /// Stack effect of `inst` at stack height `height`, checking the local
+    /// slots it refers to.
+    fn effect(&self, offset: usize, inst: Inst, height: usize) -> Result<Effect, VerifyError> {
+        let check_slot = |slot: usize| {
+            if slot < height {
+                Ok(())
+            } else {
+                Err(self.error(offset, format!("Local slot {} out of range, the frame holds {} values.", slot, height)))
+            }
+        };
+
+        let effect = match inst {
+            Inst::RETURN => Effect::new(1, 0),
+            Inst::CONSTANT { .. } => Effect::new(0, 1),
+            Inst::OP_NEGATE | Inst::OP_NOT => Effect::new(1, 1),
+            Inst::OP_ADD | Inst::OP_SUB | Inst::OP_MUL | Inst::OP_DIV
+            | Inst::OP_EQ | Inst::OP_GT | Inst::OP_LT | Inst::OP_GE | Inst::OP_LE => Effect::new(2, 1),
+            Inst::OP_PRINT | Inst::OP_POP | Inst::OP_CLOSE_UPVALUE => Effect::new(1, 0),
+            Inst::OP_DEFINE_GLOBAL { .. } => Effect::new(1, 0),
+            Inst::OP_GET_GLOBAL { .. } | Inst::OP_GET_UPVALUE { .. } | Inst::OP_CLASS { .. } => Effect::new(0, 1),
+            Inst::OP_SET_GLOBAL { .. } | Inst::OP_SET_UPVALUE { .. } => Effect::new(1, 1),
+            Inst::OP_GET_LOCAL { slot } => {
+                check_slot(slot)?;
+                Effect::new(0, 1)
+            },
+            Inst::OP_SET_LOCAL { slot } => {
+                check_slot(slot)?;
+                Effect::new(1, 1)
+            },
+            Inst::OP_JUMP { .. } | Inst::OP_LOOP { .. } => Effect::new(0, 0),
+            Inst::OP_JUMP_IF_FALSE { .. } => Effect::new(1, 1),
+            Inst::OP_CALL { argc } | Inst::OP_INVOKE { argc, .. } => Effect::new(argc + 1, 1),
+            Inst::OP_CLOSURE { idx } => {
+                let nested = self.closure_function(offset, idx)?;
+                for upvalue in nested.upvalues.iter().filter(|upvalue| upvalue.is_local) {
+                    check_slot(upvalue.index)?;
+                }
+                Effect::new(0, 1)
+            },
+            Inst::OP_GET_PROPERTY { .. } => Effect::new(1, 1),
+            Inst::OP_SET_PROPERTY { .. } | Inst::OP_GET_SUPER { .. } => Effect::new(2, 1),
+            // Both leave the class below on the stack.
+            Inst::OP_METHOD { .. } | Inst::OP_INHERIT => Effect::new(2, 1),
+            Inst::OP_SUPER_INVOKE { argc, .. } => Effect::new(argc + 2, 1),
+        };
+        Ok(effect)
+    }
+
+    /// Follow every path through the chunk, checking that the stack height is
+    /// the same whenever paths join and never reaches into slot zero.
+    fn check_stack(&self, insts: &[(usize, Inst, usize)], starts: &[Option<usize>]) -> Result<(), VerifyError> {
+        let code_len = self.function.chunk.code.len();
+        let mut heights: Vec<Option<usize>> = vec![None; insts.len()];
+        // Slot zero holds the callee, followed by the arguments.
+        heights[0] = Some(1 + self.function.arity);
+        let mut worklist = vec![0];
+
+        while let Some(idx) = worklist.pop() {
+            let (offset, inst, len) = insts[idx];
+            let height = heights[idx].expect("Visiting an instruction without a height");
+
+            let effect = self.effect(offset, inst, height)?;
+            if height < effect.pops + 1 {
+                let message = format!("Instruction pops {} values, but only {} are above slot zero.", effect.pops, height - 1);
+                return Err(self.error(offset, message));
+            }
+            let next_height = height - effect.pops + effect.pushes;
+
+            let next = offset + len;
+            let successors = match inst {
+                Inst::RETURN => vec![],
+                Inst::OP_JUMP { offset: jump } => vec![next.checked_add(jump)],
+                Inst::OP_JUMP_IF_FALSE { offset: jump } => vec![Some(next), next.checked_add(jump)],
+                Inst::OP_LOOP { offset: jump } => vec![next.checked_sub(jump)],
+                _ => vec![Some(next)],
+            };
+
+            for target in successors {
+                let target_idx = target.filter(|&target| target < code_len).and_then(|target| starts[target]);
+                let Some(target_idx) = target_idx else {
+                    let message = match target {
+                        Some(target) => format!("Jump to offset {}, which is not the start of an instruction.", target),
+                        None => "Jump out of the chunk.".to_string(),
+                    };
+                    return Err(self.error(offset, message));
+                };
+
+                match heights[target_idx] {
+                    None => {
+                        heights[target_idx] = Some(next_height);
+                        worklist.push(target_idx);
+                    },
+                    Some(known) if known != next_height => {
+                        let message = format!(
+                            "Stack height at offset {} is {} on one path and {} on another.",
+                            insts[target_idx].0, known, next_height
+                        );
+                        return Err(self.error(offset, message));
+                    },
+                    Some(_) => {},
+                }
+            }
+        }
+        Ok(())
+    }
+}
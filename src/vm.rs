@@ -1,24 +1,56 @@
-use crate::chunk::{ Chunk, Inst, KMethod };
+use crate::chunk::{ Chunk, Inst };
 use crate::value::Value;
+use crate::obj::{Obj, Function, Closure, Upvalue, Class, Instance, BoundMethod, Native, NativeFn};
+use crate::heap::{Heap, ObjRef};
+use crate::globals::Globals;
+use crate::compiler::LOCALS_MAX;
 use crate::debug::{show_value, display_inst};
 
-use std::collections::HashMap;
+use std::fmt;
 
-use phf::phf_map;
+/// Default limit on the depth of nested calls.
+pub const FRAMES_MAX: usize = 64;
+/// Default limit on the number of values on the stack.
+pub const STACK_MAX: usize = FRAMES_MAX * LOCALS_MAX;
 
-const STACK_MAX: usize = 128;
+/// An ongoing function call.
+#[derive(Debug)]
+pub struct CallFrame {
+    /// The `Obj::Closure` being executed.
+    closure: ObjRef,
+    /// The `Obj::Function` of the closure, cached for instruction fetches.
+    function: ObjRef,
+    pc: usize,
+    /// Stack index of slot zero of this frame, which holds the callee.
+    slots: usize,
+}
 
 #[derive(Debug)]
 pub struct VM {
-    pub chunk: Chunk,
-    pc: u32,
+    frames: Vec<CallFrame>,
 
+    /// Grows on demand up to `max_stack` values.
     stack: Vec<Value>,
-    sp: u32,
+    max_stack: usize,
+    max_frames: usize,
+
+    /// Global variables, addressed by the slots the compiler resolved.
+    globals: Globals,
+
+    /// Upvalues still pointing into the stack, so that closures capturing the
+    /// same variable share a single upvalue.
+    open_upvalues: Vec<ObjRef>,
 
-    globals: HashMap<String, Value>,
+    /// Every object reachable from Lox code. The roots of a collection are
+    /// the stack, the globals, the call frames and the open upvalues; the
+    /// constants of compiled code are reached through the frames.
+    heap: Heap,
+    /// The interned name of initializers, looked up on every instantiation.
+    init_string: ObjRef,
 
     enable_trace: bool,
+
+    last_error: Option<RuntimeError>,
 }
 
 #[derive(Debug)]
@@ -28,16 +60,41 @@ pub enum InterpretResult {
     RuntimeError
 }
 
-type KernalOp = fn(&mut VM);
+/// A call that was active when a runtime error occurred.
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+    /// How many identical calls directly below this one were collapsed into
+    /// it, as happens in deep recursion.
+    pub repeats: usize,
+}
 
-fn kop_print(vm: &mut VM) {
-    let v = vm.pop().expect("Expecting non-empty stack");
-    println!("{}", show_value(v));
+/// An error that aborted execution, with the line of the failing instruction
+/// and the active calls, innermost first.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub trace: Vec<TraceFrame>,
 }
 
-static KERNAL_METHODS: phf::Map<u8, KernalOp> = phf_map! {
-    0u8 => kop_print,
-};
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            match &frame.function {
+                Some(name) => write!(f, "\n[line {}] in {}()", frame.line, name)?,
+                None => write!(f, "\n[line {}] in script", frame.line)?,
+            }
+            if frame.repeats > 0 {
+                write!(f, "\n... {} more frames", frame.repeats)?;
+            }
+        }
+        Ok(())
+    }
+}
 
 type UnOp = fn(&Value) -> Value;
 
@@ -58,24 +115,6 @@ fn op_not(v: &Value) -> Value {
     }
 }
 
-fn op_add(v1: &Value, v2: &Value) -> Value {
-    match (v1, v2) {
-        (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::DOUBLE { data: x1 + x2 },
-        (Value::OBJ { data: obj1 }, Value::OBJ { data: obj2 }) => {
-            if v1.is_string() && v2.is_string() {
-                let s1 = v1.as_string().expect("Failed to convert value to string.");
-                let s2 = v2.as_string().expect("Failed to convert value to string.");
-                let mut s = s2.to_string();
-                s.push_str(s1);
-                Value::create_string_obj(s)
-            } else {
-                panic!("Could not add these two values: {:?} and {:?}", v1, v2)
-            }
-        }
-        _ => Value::EMPTY
-    }
-}
-
 fn op_sub(v1: &Value, v2: &Value) -> Value {
     match (v1, v2) {
         (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::DOUBLE { data: x2 - x1 },
@@ -97,18 +136,6 @@ fn op_div(v1: &Value, v2: &Value) -> Value {
     }
 }
 
-fn op_eq(v1: &Value, v2: &Value) -> Value {
-    match (v1, v2) {
-        (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::BOOL { data: x1 == x2 },
-        (Value::BOOL { data: x1 }, Value::BOOL { data: x2 }) => Value::BOOL { data: x1 == x2 },
-        (Value::NIL, Value::NIL) => Value::BOOL { data: true },
-        (v1, v2) if v1.is_string() && v2.is_string() => {
-            Value::BOOL { data: v1.as_string().expect("") == v2.as_string().expect("") }
-        },
-        _ => Value::BOOL { data: false },
-    }
-}
-
 fn op_gt(v1: &Value, v2: &Value) -> Value {
     match (v1, v2) {
         (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::BOOL { data: x2 > x1 },
@@ -123,30 +150,138 @@ fn op_lt(v1: &Value, v2: &Value) -> Value {
     }
 }
 
+// `>=` and `<=` are not the negations of `<` and `>`: any comparison
+// involving NaN is false.
+fn op_ge(v1: &Value, v2: &Value) -> Value {
+    match (v1, v2) {
+        (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::BOOL { data: x2 >= x1 },
+        _ => Value::EMPTY
+    }
+}
+
+fn op_le(v1: &Value, v2: &Value) -> Value {
+    match (v1, v2) {
+        (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::BOOL { data: x2 <= x1 },
+        _ => Value::EMPTY
+    }
+}
 
-macro_rules! both_matches {
-    ($e1:expr, $e2: expr, $(|)? $( $pattern:pat_param )|+ $( if $guard: expr )? $(,)?) => {
-        matches!($e1, $( $pattern )|+ $( if $guard )?) && matches!($e2, $( $pattern )|+ $( if $guard )?)
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> VM {
-        VM { chunk, pc: 0, stack: VM::create_empty_stack(), sp: 0, globals: HashMap::new(), enable_trace: false }
+    pub fn new() -> VM {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init".to_string());
+
+        VM {
+            frames: Vec::new(),
+            stack: Vec::with_capacity(LOCALS_MAX),
+            max_stack: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            globals: Globals::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            init_string,
+            enable_trace: false,
+            last_error: None,
+        }
+    }
+
+    /// Set up a call to a compiled script while keeping globals alive, so that
+    /// successive scripts observe the definitions made by earlier ones.
+    pub fn load(&mut self, function: Function) {
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.stack.clear();
+
+        // No collection until the script is rooted by its frame: the
+        // constants of the freshly compiled code are not reachable before.
+        let function = self.heap.alloc(Obj::Function { data: function });
+        let closure = self.heap.alloc(Obj::Closure { data: Closure { function, upvalues: Vec::new() } });
+        self.stack.push(Value::OBJ { data: closure });
+        self.frames.push(CallFrame { closure, function, pc: 0, slots: 0 });
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    /// The heap and the globals together, which the compiler needs at the
+    /// same time to intern names and resolve them to slots.
+    pub fn heap_and_globals_mut(&mut self) -> (&mut Heap, &mut Globals) {
+        (&mut self.heap, &mut self.globals)
     }
 
-    fn create_empty_stack() -> Vec<Value> {
-        let mut res: Vec<Value> = Vec::new();
-        for i in 0..STACK_MAX {
-            res.push(Value::EMPTY);
+    /// Allocate `obj`, collecting garbage first if the heap has grown enough.
+    /// Any object the caller still needs must be reachable from the roots.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
         }
-        res
+        self.heap.alloc(obj)
     }
 
-    pub fn runtime_error(&self, msg: String) {
-        let lineno = self.chunk.lines[self.pc as usize];
-        println!("{}", msg);
-        eprintln!("[line {}] in script", lineno);
+    pub fn collect_garbage(&mut self) {
+        for v in &self.stack {
+            self.heap.mark_value(v);
+        }
+        self.globals.mark(&mut self.heap);
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        self.heap.mark_object(self.init_string);
+
+        self.heap.collect();
+    }
+
+    /// Build a runtime error at the instruction being executed, with a trace
+    /// of the active calls, innermost first.
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let mut trace: Vec<TraceFrame> = Vec::new();
+        for frame in self.frames.iter().rev() {
+            let function = self.function(frame);
+            // The pc has already moved past the failing instruction.
+            let line = function.chunk.line_at(frame.pc.saturating_sub(1));
+            match trace.last_mut() {
+                Some(last) if last.function == function.name && last.line == line => last.repeats += 1,
+                _ => trace.push(TraceFrame { function: function.name.clone(), line, repeats: 0 }),
+            }
+        }
+        let line = trace.first().map_or(0, |frame| frame.line);
+
+        RuntimeError { message, line, trace }
+    }
+
+    /// The error that aborted the last run, if it failed.
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.last_error.as_ref()
+    }
+
+    /// Limit the number of values on the stack, beyond which execution fails
+    /// with a stack overflow.
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack;
+    }
+
+    /// Limit the depth of nested calls, beyond which execution fails with a
+    /// stack overflow.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
     }
 
     pub fn trace_on(&mut self) {
@@ -157,250 +292,677 @@ impl VM {
         self.enable_trace = false;
     }
 
-    pub fn update_global(&mut self, name: String, v: Value) {
-        self.globals.insert(name, v);
+    /// Define the global `name`, whatever the code compiled so far.
+    pub fn update_global(&mut self, name: ObjRef, v: Value) {
+        let slot = self.globals.resolve(name);
+        self.globals.set(slot, v);
+    }
+
+    /// The interned string with contents `s`, collecting garbage first if a
+    /// new string might have to be allocated.
+    pub fn intern(&mut self, s: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(s)
+    }
+
+    /// The contents of an interned name.
+    fn name_str(&self, name: ObjRef) -> &str {
+        self.heap.get(name).as_str().unwrap_or("?")
     }
 
-    fn unop_typecheck(&mut self, checker: fn(&Value) -> bool, desc: &str) -> bool {
-        let v = self.peek();
-        let res = checker(&v);
+    /// Make a host function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native { name: name.to_string(), arity, function };
+        let name = self.intern(name.to_string());
+
+        // Keep the name on the stack while the native is allocated.
+        self.stack.push(Value::OBJ { data: name });
+        let native = self.alloc(Obj::Native { data: native });
+        self.stack.pop();
+
+        self.update_global(name, Value::OBJ { data: native });
+    }
+
+    fn unop_typecheck(&mut self, checker: fn(&Value) -> bool, desc: &str) -> Result<(), RuntimeError> {
+        let v = self.peek()?;
+        let res = checker(v);
 
         if !res {
-            self.runtime_error(format!("Expecting operand of type {}", desc));
+            return Err(self.runtime_error(format!("Expecting operand of type {}", desc)));
         }
 
-        res
+        Ok(())
     }
 
-    fn binop_typecheck(&mut self, checker: fn(&Value) -> bool, desc: &str) -> bool {
-        let v1 = self.peek_at(0);
-        let v2 = self.peek_at(1);
+    fn binop_typecheck(&mut self, checker: fn(&Value) -> bool, desc: &str) -> Result<(), RuntimeError> {
+        let v1 = self.peek_at(0)?;
+        let v2 = self.peek_at(1)?;
 
-        let res = checker(&v1) && checker(&v2);
+        let res = checker(v1) && checker(v2);
 
         if !res {
-            self.runtime_error(format!("Expecting operands of type {}", desc));
+            return Err(self.runtime_error(format!("Expecting operands of type {}", desc)));
         }
 
-        res
+        Ok(())
+    }
+
+    fn lift_unop(&mut self, op: UnOp) -> Result<(), RuntimeError> {
+        let v = self.pop()?;
+        self.push(op(&v))
     }
 
-    fn binop_typecheck_both(&mut self, checker: fn(&Value, &Value) -> bool, desc: &str) -> bool {
-        let v1 = self.peek_at(0);
-        let v2 = self.peek_at(1);
+    fn lift_binop(&mut self, op: BinOp) -> Result<(), RuntimeError> {
+        let v1 = self.pop()?;
+        let v2 = self.pop()?;
+        self.push(op(&v1, &v2))
+    }
 
-        let res = checker(&v1, &v2);
+    /// Equality of Lox values: strings by contents, other objects by identity.
+    fn values_equal(&self, v1: &Value, v2: &Value) -> bool {
+        match (v1, v2) {
+            (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => x1 == x2,
+            (Value::BOOL { data: x1 }, Value::BOOL { data: x2 }) => x1 == x2,
+            (Value::NIL, Value::NIL) => true,
+            // Strings are interned, so equal strings are the same object.
+            (Value::OBJ { data: o1 }, Value::OBJ { data: o2 }) => o1 == o2,
+            _ => false,
+        }
+    }
 
-        if !res {
-            self.runtime_error(format!("Expecting operands of type {}", desc));
+    /// Add two numbers, or concatenate two strings.
+    fn add(&mut self) -> Result<(), RuntimeError> {
+        let v1 = self.peek_at(0)?;
+        let v2 = self.peek_at(1)?;
+
+        let result = match (v1, v2) {
+            (Value::DOUBLE { data: x1 }, Value::DOUBLE { data: x2 }) => Value::DOUBLE { data: x2 + x1 },
+            _ => {
+                let s = match (self.heap.as_string(v2), self.heap.as_string(v1)) {
+                    (Some(s2), Some(s1)) => format!("{}{}", s2, s1),
+                    _ => return Err(self.runtime_error("Expecting operands of type number or string".to_string())),
+                };
+                // The operands are no longer needed once their contents are copied.
+                self.pop()?;
+                self.pop()?;
+                let s = self.intern(s);
+                return self.push(Value::OBJ { data: s });
+            },
+        };
+
+        self.pop()?;
+        self.pop()?;
+        self.push(result)
+    }
+
+    fn read_name(&self, name_idx: usize) -> Result<ObjRef, RuntimeError> {
+        match self.chunk().value_array.read(name_idx) {
+            Value::OBJ { data } if self.heap.get(data).as_str().is_some() => Ok(data),
+            _ => Err(self.runtime_error("Expecting string as variable name".to_string())),
         }
+    }
 
-        res
+    fn undefined_variable(&self, slot: usize) -> RuntimeError {
+        let name = self.globals.name(slot).map_or("?", |name| self.name_str(name));
+        self.runtime_error(format!("Undefined variable: {}", name))
     }
 
-    fn lift_unop(&mut self, op: UnOp) {
-        match self.pop() {
-            Option::None => self.push(Value::EMPTY),
-            Option::Some(v0) => {
-                let v1 = op(&v0);
-                self.push(v1)
-            }
+    fn define_variable(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        if slot >= self.globals.len() {
+            return Err(self.undefined_variable(slot));
+        }
+
+        let v = self.pop()?;
+        self.globals.set(slot, v);
+        Ok(())
+    }
+
+    fn get_variable(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        match self.globals.get(slot) {
+            Some(v) => {
+                let v = v.clone();
+                self.push(v)
+            },
+            None => Err(self.undefined_variable(slot)),
+        }
+    }
+
+    fn set_variable(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        if !self.globals.is_defined(slot) {
+            return Err(self.undefined_variable(slot));
         }
+
+        // Assignment is an expression, so the value stays on the stack.
+        let v = self.peek()?.clone();
+        self.globals.set(slot, v);
+        Ok(())
     }
 
-    fn lift_binop(&mut self, op: BinOp) {
-        let v1;
-        let v2;
+    fn call_value(&mut self, argc: usize) -> Result<(), RuntimeError> {
+        let callee = self.peek_at(argc)?.clone();
 
-        match self.pop() {
-            Option::None => { self.push(Value::EMPTY); return; },
-            Option::Some(v) => {
-                v1 = v.clone();
+        if let Value::OBJ { data } = callee {
+            match self.heap.get(data) {
+                Obj::Closure { data: _ } => {
+                    return self.call(data, argc);
+                },
+                Obj::Native { data: native } => {
+                    return self.call_native(native.arity, native.function, argc);
+                },
+                Obj::BoundMethod { data: bound } => {
+                    // The receiver takes the place of the callee, as `this`.
+                    let method = bound.method;
+                    let slot = self.stack.len() - argc - 1;
+                    self.stack[slot] = bound.receiver.clone();
+                    return self.call(method, argc);
+                },
+                Obj::Class { data: class } => {
+                    let initializer = class.find_method(self.init_string);
+
+                    // The instance takes the place of the class on the stack,
+                    // which keeps the class alive while allocating.
+                    let instance = self.alloc(Obj::Instance { data: Instance::new(data) });
+                    let slot = self.stack.len() - argc - 1;
+                    self.stack[slot] = Value::OBJ { data: instance };
+
+                    return match initializer {
+                        Some(initializer) => self.call(initializer, argc),
+                        None if argc != 0 => Err(self.runtime_error(format!("Expected 0 arguments but got {}.", argc))),
+                        None => Ok(()),
+                    };
+                },
+                _ => {},
             }
         }
 
-        match self.pop() {
-            Option::None => { self.push(Value::EMPTY); return; },
-            Option::Some(v) => {
-                v2 = v.clone();
+        Err(self.runtime_error("Can only call functions and classes.".to_string()))
+    }
+
+    fn call(&mut self, closure: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.get(closure).as_closure().expect("Calling a non-closure").function;
+        let arity = self.heap.get(function).as_function().expect("Closure over a non-function").arity;
+        if argc != arity {
+            return Err(self.runtime_error(format!("Expected {} arguments but got {}.", arity, argc)));
+        }
+
+        if self.frames.len() >= self.max_frames {
+            return Err(self.runtime_error("Stack overflow.".to_string()));
+        }
+
+        let slots = self.stack.len() - argc - 1;
+        self.frames.push(CallFrame { closure, function, pc: 0, slots });
+        Ok(())
+    }
+
+    fn call_native(&mut self, arity: usize, function: NativeFn, argc: usize) -> Result<(), RuntimeError> {
+        if argc != arity {
+            return Err(self.runtime_error(format!("Expected {} arguments but got {}.", arity, argc)));
+        }
+
+        let slot = self.stack.len() - argc - 1;
+        // The heap does not collect while the native allocates, so the
+        // arguments stay alive.
+        match function(&mut self.heap, &self.stack[slot + 1..]) {
+            Ok(result) => {
+                // Discard the callee and the arguments.
+                self.stack.truncate(slot);
+                self.push(result)?;
+                Ok(())
+            },
+            Err(msg) => Err(self.runtime_error(msg)),
+        }
+    }
+
+    /// Find or create the upvalue for the given stack slot.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        for &upvalue in &self.open_upvalues {
+            if let Some(Upvalue::Open { slot: open_slot }) = self.heap.get(upvalue).as_upvalue() {
+                if *open_slot == slot {
+                    return upvalue;
+                }
             }
         }
 
-        self.push(op(&v1, &v2))
+        let upvalue = self.alloc(Obj::Upvalue { data: Upvalue::Open { slot } });
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Move every variable living at or above `last` off the stack into the
+    /// upvalue capturing it.
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+
+        self.open_upvalues.retain(|&upvalue| {
+            let upvalue = heap.get_mut(upvalue).as_upvalue_mut().expect("Expecting upvalue");
+            match *upvalue {
+                Upvalue::Open { slot } if slot >= last => {
+                    *upvalue = Upvalue::Closed { value: stack[slot].clone() };
+                    false
+                },
+                _ => true,
+            }
+        });
+    }
+
+    fn make_closure(&mut self, function: ObjRef) -> Value {
+        let descs = self.heap.get(function).as_function().expect("Expecting function constant").upvalues.clone();
+        let slots = self.frame().slots;
+
+        // Captured upvalues stay reachable through `open_upvalues` or the
+        // enclosing closure until the new closure owns them.
+        let upvalues = descs.iter().map(|desc| {
+            if desc.is_local {
+                self.capture_upvalue(slots + desc.index)
+            } else {
+                self.closure(self.frame()).upvalues[desc.index]
+            }
+        }).collect();
+
+        Value::OBJ { data: self.alloc(Obj::Closure { data: Closure { function, upvalues } }) }
+    }
+
+    fn as_instance(&self, v: &Value) -> Option<&Instance> {
+        v.as_obj().and_then(|r| self.heap.get(r).as_instance())
     }
 
-    fn read_name(&self, name_idx: usize) -> String {
-        let v = self.chunk.value_array.read(name_idx);
-        let varname = v.as_string().expect("Expecting string as variable name");
-        varname.to_string()
+    fn as_class(&self, class: ObjRef) -> &Class {
+        self.heap.get(class).as_class().expect("Expecting class")
     }
 
-    fn define_variable(&mut self, name_idx: usize) {
-        let varname = self.read_name(name_idx);
-        let v = self.pop().expect("Expecting non-empty stack").clone();
-        self.update_global(varname.into(), v);
+    fn get_property(&mut self, name_idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_name(name_idx)?;
+
+        let (field, class) = match self.as_instance(self.peek()?) {
+            Some(instance) => (instance.fields.get(&name).cloned(), instance.class),
+            None => {
+                return Err(self.runtime_error("Only instances have properties.".to_string()));
+            }
+        };
+
+        match field {
+            Some(v) => {
+                self.pop()?;
+                self.push(v)?;
+                Ok(())
+            },
+            None => self.bind_method(class, name),
+        }
     }
 
-    fn get_variable(&mut self, name_idx: usize) {
-        let varname = self.read_name(name_idx);
-        let v = self.globals.get(&varname);
+    /// Replace the instance on top of the stack with the method `name` of
+    /// `class`, bound to the instance.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let receiver = self.peek()?.clone();
 
-        match v {
-            Option::None => {
-                let msg = format!("Undefined variable: {}", varname);
-                self.runtime_error(msg);
+        match self.as_class(class).find_method(name) {
+            Some(method) => {
+                // The receiver stays on the stack until the bound method
+                // replaces it.
+                let bound = self.alloc(Obj::BoundMethod { data: BoundMethod { receiver, method } });
+                self.pop()?;
+                self.push(Value::OBJ { data: bound })?;
+                Ok(())
             },
-            Option::Some(v) => {
-                let v0 = v.clone();
-                self.push(v0);
+            None => Err(self.runtime_error(format!("Undefined property '{}'.", self.name_str(name)))),
+        }
+    }
+
+    fn invoke(&mut self, name_idx: usize, argc: usize) -> Result<(), RuntimeError> {
+        let name = self.read_name(name_idx)?;
+
+        let instance = match self.as_instance(self.peek_at(argc)?) {
+            Some(instance) => instance,
+            None => {
+                return Err(self.runtime_error("Only instances have methods.".to_string()));
+            }
+        };
+
+        // A field holding a function shadows the method of the same name.
+        let class = instance.class;
+        if let Some(field) = instance.fields.get(&name).cloned() {
+            let slot = self.stack.len() - argc - 1;
+            self.stack[slot] = field;
+            return self.call_value(argc);
+        }
+
+        self.invoke_from_class(class, name, argc)
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        match self.as_class(class).find_method(name) {
+            Some(method) => self.call(method, argc),
+            None => Err(self.runtime_error(format!("Undefined property '{}'.", self.name_str(name)))),
+        }
+    }
+
+    fn pop_superclass(&mut self) -> Result<ObjRef, RuntimeError> {
+        match self.pop()? {
+            Value::OBJ { data } if self.heap.get(data).as_class().is_some() => Ok(data),
+            _ => Err(self.runtime_error("Superclass must be a class.".to_string())),
+        }
+    }
+
+    fn set_property(&mut self, name_idx: usize) -> Result<(), RuntimeError> {
+        let name = self.read_name(name_idx)?;
+        let v = self.peek()?.clone();
+
+        let instance = self.peek_at(1)?.as_obj();
+        match instance.and_then(|r| self.heap.get_mut(r).as_instance_mut()) {
+            Some(instance) => {
+                instance.fields.insert(name, v.clone());
             },
+            None => {
+                return Err(self.runtime_error("Only instances have fields.".to_string()));
+            }
+        }
+
+        // Pop the value and the instance, leaving the value as the result.
+        self.pop()?;
+        self.pop()?;
+        self.push(v)?;
+        Ok(())
+    }
+
+    fn upvalue(&self, idx: usize) -> ObjRef {
+        self.closure(self.frame()).upvalues[idx]
+    }
+
+    fn read_upvalue(&self, idx: usize) -> Value {
+        match self.heap.get(self.upvalue(idx)).as_upvalue().expect("Expecting upvalue") {
+            Upvalue::Open { slot } => self.stack[*slot].clone(),
+            Upvalue::Closed { value } => value.clone(),
+        }
+    }
+
+    fn write_upvalue(&mut self, idx: usize, v: Value) {
+        let upvalue = self.upvalue(idx);
+        match self.heap.get_mut(upvalue).as_upvalue_mut().expect("Expecting upvalue") {
+            Upvalue::Open { slot } => self.stack[*slot] = v,
+            Upvalue::Closed { value } => *value = v,
         }
     }
 
+    /// Run the loaded script to completion. On a runtime error the stack is
+    /// reset and the error is kept for `last_error`.
     pub fn run(&mut self) -> InterpretResult {
-        let res = loop {
+        match self.execute() {
+            Ok(()) => {
+                self.last_error = None;
+                InterpretResult::Ok
+            },
+            Err(err) => {
+                self.frames.clear();
+                self.open_upvalues.clear();
+                self.stack.clear();
+                self.last_error = Some(err);
+                InterpretResult::RuntimeError
+            },
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        loop {
+
+            let (inst, len) = self.fetch()?;
 
             if self.enable_trace {
                 self.display_stack();
                 self.display_globals();
-                display_inst(&self.chunk.data[self.pc as usize], &self.chunk)
+                display_inst(&self.heap, &self.globals, &inst, self.frame().pc, self.chunk())
             }
 
-            let inst = self.fetch();
+            self.frame_mut().pc += len;
 
             match inst {
                 Inst::RETURN => {
-                    break InterpretResult::Ok
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().expect("Returning without a call frame");
+                    self.close_upvalues(frame.slots);
+
+                    if self.frames.is_empty() {
+                        self.stack.clear();
+                        return Ok(());
+                    }
+
+                    // Discard the arguments and locals of the callee.
+                    self.stack.truncate(frame.slots);
+                    self.push(result)?;
+                },
+                Inst::OP_CALL { argc } => {
+                    self.call_value(argc)?;
                 },
                 Inst::OP_POP => {
-                    self.pop();
+                    self.pop()?;
+                },
+                Inst::OP_PRINT => {
+                    let v = self.pop()?;
+                    println!("{}", show_value(&self.heap, &v));
+                },
+                Inst::OP_CLOSURE { idx } => {
+                    let function = match self.chunk().value_array.read(idx) {
+                        Value::OBJ { data } if self.heap.get(data).as_function().is_some() => data,
+                        _ => return Err(self.runtime_error("Expecting function constant".to_string())),
+                    };
+                    let closure = self.make_closure(function);
+                    self.push(closure)?;
+                },
+                Inst::OP_GET_UPVALUE { idx } => {
+                    let v = self.read_upvalue(idx);
+                    self.push(v)?;
+                },
+                Inst::OP_SET_UPVALUE { idx } => {
+                    let v = self.peek()?.clone();
+                    self.write_upvalue(idx, v);
+                },
+                Inst::OP_CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop()?;
+                },
+                Inst::OP_CLASS { name_idx } => {
+                    let name = self.read_name(name_idx)?;
+                    let class = self.alloc(Obj::Class { data: Class::new(self.name_str(name).to_string()) });
+                    self.push(Value::OBJ { data: class })?;
+                },
+                Inst::OP_METHOD { name_idx } => {
+                    let name = self.read_name(name_idx)?;
+                    let method = match self.peek()? {
+                        Value::OBJ { data } if self.heap.get(*data).as_closure().is_some() => *data,
+                        _ => return Err(self.runtime_error("Expecting method closure".to_string())),
+                    };
+                    let class = self.peek_at(1)?.as_obj();
+                    if let Some(class) = class.and_then(|r| self.heap.get_mut(r).as_class_mut()) {
+                        class.methods.insert(name, method);
+                    }
+                    self.pop()?;
                 },
-                Inst::OP_KCALL { tp } => {
-                    let kop = KERNAL_METHODS.get(&(tp.clone() as u8)).expect("Unsupported kernal method");
-                    kop(self);
+                Inst::OP_INVOKE { name_idx, argc } => {
+                    self.invoke(name_idx, argc)?;
                 },
-                Inst::OP_DEFINE_GLOBAL { name_idx } => {
-                    let idx = name_idx.clone();
-                    self.define_variable(idx);
+                Inst::OP_INHERIT => {
+                    let superclass = self.peek_at(1)?.as_obj();
+                    let methods = match superclass.and_then(|r| self.heap.get(r).as_class()) {
+                        Some(superclass) => superclass.methods.clone(),
+                        None => return Err(self.runtime_error("Superclass must be a class.".to_string())),
+                    };
+                    let subclass = self.peek()?.as_obj();
+                    if let Some(subclass) = subclass.and_then(|r| self.heap.get_mut(r).as_class_mut()) {
+                        subclass.methods.extend(methods);
+                    }
+                    self.pop()?;
                 },
-                Inst::OP_GET_GLOBAL { name_idx } => {
-                    let idx = name_idx.clone();
-                    self.get_variable(idx);
+                Inst::OP_GET_SUPER { name_idx } => {
+                    let name = self.read_name(name_idx)?;
+                    let superclass = self.pop_superclass()?;
+                    self.bind_method(superclass, name)?;
+                },
+                Inst::OP_SUPER_INVOKE { name_idx, argc } => {
+                    let name = self.read_name(name_idx)?;
+                    let superclass = self.pop_superclass()?;
+                    self.invoke_from_class(superclass, name, argc)?;
+                },
+                Inst::OP_GET_PROPERTY { name_idx } => {
+                    self.get_property(name_idx)?;
+                },
+                Inst::OP_SET_PROPERTY { name_idx } => {
+                    self.set_property(name_idx)?;
+                },
+                Inst::OP_DEFINE_GLOBAL { slot } => {
+                    self.define_variable(slot)?;
+                },
+                Inst::OP_GET_GLOBAL { slot } => {
+                    self.get_variable(slot)?;
+                },
+                Inst::OP_SET_GLOBAL { slot } => {
+                    self.set_variable(slot)?;
+                },
+                Inst::OP_GET_LOCAL { slot } => {
+                    let v = self.stack[self.frame().slots + slot].clone();
+                    self.push(v)?;
+                },
+                Inst::OP_SET_LOCAL { slot } => {
+                    let idx = self.frame().slots + slot;
+                    self.stack[idx] = self.peek()?.clone();
+                },
+                Inst::OP_JUMP { offset } => {
+                    self.frame_mut().pc += offset;
+                },
+                Inst::OP_LOOP { offset } => {
+                    self.frame_mut().pc -= offset;
+                },
+                Inst::OP_JUMP_IF_FALSE { offset } => {
+                    if self.peek()?.is_falsey() {
+                        self.frame_mut().pc += offset;
+                    }
                 },
                 Inst::CONSTANT { idx } => {
-                    let val = self.chunk.value_array.read(*idx);
-                    self.push(val);
+                    let val = self.chunk().value_array.read(idx);
+                    self.push(val)?;
                 },
                 Inst::OP_NEGATE => {
-                    self.unop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_unop(op_negate)
+                    self.unop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_unop(op_negate)?;
                 },
                 Inst::OP_NOT => {
-                    self.unop_typecheck(|v| matches!(v, Value::BOOL { data: _ }) || matches!(v, Value::NIL), "boolean or nil");
-                    self.lift_unop(op_not)
+                    self.unop_typecheck(|v| matches!(v, Value::BOOL { data: _ }) || matches!(v, Value::NIL), "boolean or nil")?;
+                    self.lift_unop(op_not)?;
                 }
                 Inst::OP_ADD => {
-                    self.binop_typecheck_both(
-                        |v1, v2| both_matches!(v1, v2, Value::DOUBLE { data: _ }) || (v1.is_string() && v2.is_string()),
-                        "number or string"
-                    );
-                    self.lift_binop(op_add)
+                    self.add()?;
                 },
                 Inst::OP_SUB => {
-                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_binop(op_sub)
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_sub)?;
                 },
                 Inst::OP_DIV => {
-                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_binop(op_div)
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_div)?;
                 },
                 Inst::OP_MUL => {
-                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_binop(op_mul)
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_mul)?;
                 },
                 Inst::OP_EQ => {
-                    self.lift_binop(op_eq)
+                    let v1 = self.pop()?;
+                    let v2 = self.pop()?;
+                    let eq = self.values_equal(&v1, &v2);
+                    self.push(Value::BOOL { data: eq })?;
                 },
                 Inst::OP_GT => {
-                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_binop(op_gt)
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_gt)?;
                 },
                 Inst::OP_LT => {
-                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number");
-                    self.lift_binop(op_lt)
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_lt)?;
+                },
+                Inst::OP_GE => {
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_ge)?;
+                },
+                Inst::OP_LE => {
+                    self.binop_typecheck(|v| matches!(v, Value::DOUBLE { data: _ }), "number")?;
+                    self.lift_binop(op_le)?;
                 },
-                _ => break InterpretResult::RuntimeError
             }
+        }
+    }
 
-            self.step();
-        };
-        res
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Running without a call frame")
     }
 
-    pub fn fetch(&self) -> &Inst {
-        &self.chunk.data[self.pc as usize]
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Running without a call frame")
     }
 
-    pub fn step(&mut self) {
-        self.pc = self.pc + 1;
+    fn closure(&self, frame: &CallFrame) -> &Closure {
+        self.heap.get(frame.closure).as_closure().expect("Call frame without a closure")
     }
 
-    pub fn push(&mut self, value: Value) {
-        let idx = self.sp as usize;
-        self.stack[idx] = value;
-        self.sp += 1;
+    fn function(&self, frame: &CallFrame) -> &Function {
+        self.heap.get(frame.function).as_function().expect("Call frame without a function")
     }
 
-    pub fn pop(&mut self) -> Option<&Value> {
-        if self.sp <= 0 {
-            None
-        } else {
-            self.sp -= 1;
-            Some(&self.stack[self.sp as usize])
-        }
+    fn chunk(&self) -> &Chunk {
+        &self.function(self.frame()).chunk
     }
 
-    pub fn peek_opt(&self) -> Option<&Value> {
-        if self.sp <= 0 {
-            None
-        } else {
-            Some(&self.stack[self.sp as usize - 1])
-        }
+    /// Decode the next instruction, along with its length in bytes.
+    fn fetch(&self) -> Result<(Inst, usize), RuntimeError> {
+        self.chunk().decode(self.frame().pc)
+            .ok_or_else(|| self.runtime_error("Malformed bytecode.".to_string()))
     }
 
-    pub fn peek_at_opt(&self, idx: u32) -> Option<&Value> {
-        let i = self.sp - idx;
+    pub fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.max_stack {
+            return Err(self.runtime_error("Stack overflow.".to_string()));
+        }
+
+        self.stack.push(value);
+        Ok(())
+    }
 
-        if i <= 0 {
-            None
-        } else {
-            Some(&self.stack[i as usize - 1])
+    pub fn pop(&mut self) -> Result<Value, RuntimeError> {
+        match self.stack.pop() {
+            Some(v) => Ok(v),
+            None => Err(self.stack_underflow()),
         }
     }
 
-    pub fn peek(&self) -> &Value {
-        self.peek_opt().expect("Peeking value, but found an empty stack")
+    pub fn peek(&self) -> Result<&Value, RuntimeError> {
+        self.peek_at(0)
+    }
+
+    /// The value `i` slots below the top of the stack.
+    pub fn peek_at(&self, i: usize) -> Result<&Value, RuntimeError> {
+        match self.stack.len().checked_sub(i + 1) {
+            Some(idx) => Ok(&self.stack[idx]),
+            None => Err(self.stack_underflow()),
+        }
     }
 
-    pub fn peek_at(&self, i: u32) -> &Value {
-        self.peek_at_opt(i).expect("Peeking value, but found an empty stack")
+    fn stack_underflow(&self) -> RuntimeError {
+        self.runtime_error("Stack underflow.".to_string())
     }
 
     pub fn display_stack(&self) {
         print!(" STACK: ");
-        for i in 0..self.sp {
-            let value = &self.stack[i as usize];
-            print!("[ {} ] ", show_value(value));
+        for value in &self.stack {
+            print!("[ {} ] ", show_value(&self.heap, value));
         }
-        println!("");
+        println!();
     }
 
     pub fn display_globals(&self) {
         print!(" GLOBALS: ");
-        for (k, v) in &self.globals {
-            print!("{} => {}; ", k, show_value(v));
+        for (name, v) in self.globals.iter() {
+            print!("{} => {}; ", self.name_str(name), show_value(&self.heap, v));
         }
-        println!("");
+        println!();
     }
 }
 
@@ -0,0 +1,153 @@
+use rlox::asm::{assemble, AsmError};
+use rlox::compiler::Compiler;
+use rlox::debug::disassemble;
+use rlox::driver::Driver;
+use rlox::globals::Globals;
+use rlox::heap::Heap;
+use rlox::obj::Function;
+use rlox::value::Value;
+use rlox::vm::InterpretResult;
+
+const PROGRAMS: &[&str] = &[
+    "var a = 1; { var b = a + 2; print b; } a = a * 3;",
+    "for (var i = 0; i < 3; i = i + 1) { if (i == 1 and true or false) print i; else print -i; }",
+    "fun make() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } var c = make(); print c();",
+    "class A { init(x) { this.x = x; } get() { return this.x; } }
+     class B < A { get() { return super.get() + 1; } other() { var m = super.get; return m(); } }
+     print B(1).get(); print B(2).other();",
+    "var s = \"semi;colon\" + \"back\\slash\" + \"line\nbreak\"; print s; print 0.1 + 0.2;",
+];
+
+const DOUBLE: &str = "
+.function f0 - 0
+.constants
+    0: function f1
+    1: 21
+.code
+.line 1
+    CLOSURE 0
+    DEFINE_GLOBAL double
+.line 2
+    GET_GLOBAL double
+    CONSTANT 1
+    CALL 1
+    DEFINE_GLOBAL result
+    CONSTANT 1
+    RETURN
+.end
+
+.function f1 double 1
+.constants
+.code
+.line 1
+    GET_LOCAL 1
+    GET_LOCAL 1
+    ADD
+    RETURN
+.end
+";
+
+fn text(heap: &Heap, globals: &Globals, function: &Function) -> String {
+    let mut out = String::new();
+    disassemble(&mut out, heap, globals, function).unwrap();
+    out
+}
+
+fn assemble_error(source: &str) -> AsmError {
+    assemble(source, &mut Heap::new(), &mut Globals::new()).expect_err("Expecting the assembly to be rejected")
+}
+
+#[test]
+fn disassembly_assembles_back_to_the_same_code() {
+    for source in PROGRAMS {
+        let mut heap = Heap::new();
+        let mut globals = Globals::new();
+        let compiled = Compiler::new(source.to_string(), &mut heap, &mut globals).compile()
+            .expect("Expecting the program to compile");
+        let listing = text(&heap, &globals, &compiled);
+
+        let assembled = match assemble(&listing, &mut heap, &mut globals) {
+            Ok(function) => function,
+            Err(err) => panic!("{}\n{}", err, listing),
+        };
+        assert_eq!(assembled.chunk.code, compiled.chunk.code, "{}", source);
+        assert_eq!(text(&heap, &globals, &assembled), listing);
+    }
+}
+
+#[test]
+fn hand_written_assembly_runs() {
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret_asm(DOUBLE), Ok(InterpretResult::Ok)));
+    assert!(matches!(driver.global("result"), Some(Value::DOUBLE { data }) if *data == 42.0));
+
+    // Assembled files load like compiled ones.
+    let bytes = Driver::new().assemble(DOUBLE).expect("Expecting the assembly to succeed");
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret_compiled(&bytes), Ok(InterpretResult::Ok)));
+    assert!(matches!(driver.global("result"), Some(Value::DOUBLE { data }) if *data == 42.0));
+}
+
+#[test]
+fn labels_resolve_forwards_and_backwards() {
+    // var n = 0; while (n < 3) n = n + 1;
+    let source = "
+.function f0 - 0
+.constants
+    0: 0
+    1: 3
+    2: 1
+    3: nil
+.code
+    CONSTANT 0
+    DEFINE_GLOBAL n
+loop:
+    GET_GLOBAL n
+    CONSTANT 1
+    LT
+    JUMP_IF_FALSE done
+    POP
+    GET_GLOBAL n
+    CONSTANT 2
+    ADD
+    SET_GLOBAL n
+    POP
+    LOOP loop
+done:
+    POP
+    CONSTANT 3
+    RETURN
+.end
+";
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret_asm(source), Ok(InterpretResult::Ok)));
+    assert!(matches!(driver.global("n"), Some(Value::DOUBLE { data }) if *data == 3.0));
+}
+
+#[test]
+fn syntax_errors_are_reported_with_lines() {
+    let err = assemble_error(".function f0 - 0\n.code\n    FROB\n    RETURN\n.end\n");
+    assert_eq!(err.to_string(), "[line 3] Assembly error: Unknown instruction FROB.");
+
+    let err = assemble_error(".function f0 - 0\n.code\n    JUMP nowhere\n.end\n");
+    assert_eq!(err.to_string(), "[line 3] Assembly error: Unknown label nowhere.");
+
+    let err = assemble_error(".function f0 - 0\n.code\n    GET_LOCAL 256\n.end\n");
+    assert_eq!(err.to_string(), "[line 3] Assembly error: Expecting a number up to 255, found '256'.");
+
+    let err = assemble_error(".function f0 - 0\n.constants\n    0: 1\n    1: 1\n.end\n");
+    assert_eq!(err.to_string(), "[line 4] Assembly error: Duplicate of constant 0.");
+
+    let err = assemble_error(".function f0 - 0\n.code\nback:\n    JUMP back\n.end\n");
+    assert_eq!(err.to_string(), "[line 4] Assembly error: JUMP can only jump forwards.");
+
+    let err = assemble_error(".function f0 - 0\n.constants\n    0: function f0\n.end\n");
+    assert_eq!(err.to_string(), "[line 3] Assembly error: Function f0 contains itself.");
+}
+
+#[test]
+fn unsafe_assembly_is_rejected() {
+    let err = assemble_error(".function f0 - 0\n.code\n    POP\n    RETURN\n.end\n");
+    assert_eq!(err.line, None);
+    assert!(err.message.starts_with("Instruction pops 1 values"), "{}", err);
+}
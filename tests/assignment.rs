@@ -0,0 +1,19 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn assignment_updates_globals() {
+    let run = run_script("var a = 1;\na = a + 2;\nprint a;\nvar b = a = 10;\nprint a + b;\n");
+    assert_eq!(run.lines(), ["3", "20"]);
+    assert_eq!(run.code, Some(0));
+}
+
+#[test]
+fn invalid_assignment_targets_are_compile_errors() {
+    for source in ["var a = 1; var b = 2;\na + b = 3;", "var a = 1;\n-a = 3;", "var a = 1;\n(a) = 3;"] {
+        let run = run_script(source);
+        assert_eq!(run.code, Some(65), "{}", source);
+        assert!(run.stdout.contains("[line 2] Error at = : Invalid assignment target."), "{}", run.stdout);
+    }
+}
@@ -0,0 +1,75 @@
+use rlox::chunk::{Chunk, Inst};
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+fn decode_all(chunk: &Chunk) -> Vec<Inst> {
+    let mut insts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (inst, len) = chunk.decode(offset).expect("Expecting a well-formed chunk");
+        insts.push(inst);
+        offset += len;
+    }
+    insts
+}
+
+#[test]
+fn instructions_survive_encoding() {
+    let insts = [
+        Inst::CONSTANT { idx: 3 },
+        Inst::CONSTANT { idx: 300 },
+        Inst::OP_GET_GLOBAL { slot: 255 },
+        Inst::OP_SET_GLOBAL { slot: 256 },
+        Inst::OP_GET_LOCAL { slot: 255 },
+        Inst::OP_JUMP { offset: 65535 },
+        Inst::OP_LOOP { offset: 7 },
+        Inst::OP_INVOKE { name_idx: 1000, argc: 255 },
+        Inst::OP_SUPER_INVOKE { name_idx: 2, argc: 0 },
+        Inst::OP_CLOSE_UPVALUE,
+        Inst::RETURN,
+    ];
+
+    let mut chunk = Chunk::new();
+    for inst in insts {
+        chunk.write(inst, 1);
+    }
+
+    assert_eq!(decode_all(&chunk), insts);
+    assert_eq!(chunk.code.len(), 2 + 3 + 2 + 3 + 2 + 3 + 3 + 4 + 3 + 1 + 1);
+}
+
+#[test]
+fn lines_are_run_length_encoded() {
+    let mut chunk = Chunk::new();
+    chunk.write(Inst::CONSTANT { idx: 0 }, 1);
+    chunk.write(Inst::OP_PRINT, 1);
+    chunk.write(Inst::CONSTANT { idx: 0 }, 3);
+    chunk.write(Inst::OP_NEGATE, 3);
+    chunk.write(Inst::RETURN, 4);
+
+    assert_eq!(chunk.lines.len(), 3);
+    let lines: Vec<usize> = (0..chunk.code.len()).map(|offset| chunk.line_at(offset)).collect();
+    assert_eq!(lines, [1, 1, 1, 3, 3, 3, 4]);
+}
+
+#[test]
+fn long_operands_run() {
+    let mut driver = Driver::new();
+    let source: String = (0..300).map(|n| format!("var g{} = {};", n, n)).collect();
+    assert!(matches!(driver.interpret(source + "var sum = g0 + g299;"), InterpretResult::Ok));
+    assert!(matches!(driver.interpret("if (sum != 299) nil();".to_string()), InterpretResult::Ok));
+}
+
+#[test]
+fn oversized_jumps_are_compile_errors() {
+    let mut driver = Driver::new();
+    let body = "print 1;".repeat(30_000);
+    let sources = [
+        format!("if (true) {{ {} }}", body),
+        format!("while (false) {{ {} }}", body),
+    ];
+
+    for source in sources {
+        assert!(matches!(driver.interpret(source), InterpretResult::CompileError));
+    }
+}
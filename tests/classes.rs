@@ -0,0 +1,40 @@
+mod common;
+
+use common::{run_script, runtime_error};
+
+#[test]
+fn instances_hold_their_own_fields() {
+    let source = "
+class Point {}
+var p = Point();
+var q = Point();
+p.x = 1;
+p.y = p.x + 1;
+q.x = 100;
+print p.x + p.y;
+print q.x;
+print p.x = 10;
+print p;
+";
+    let run = run_script(source);
+    assert_eq!(run.lines(), ["3", "100", "10", "Point instance"]);
+    assert_eq!(run.code, Some(0));
+}
+
+#[test]
+fn missing_fields_are_runtime_errors() {
+    let source = "class A {}\nvar a = A();\nprint a.missing;";
+    assert_eq!(runtime_error(source), ("Undefined property 'missing'.".to_string(), 3));
+}
+
+#[test]
+fn only_instances_have_fields() {
+    assert_eq!(runtime_error("var n = 1; print n.x;").0, "Only instances have properties.");
+    assert_eq!(runtime_error("class A {} print A.x;").0, "Only instances have properties.");
+    assert_eq!(runtime_error("var s = \"s\"; s.x = 1;").0, "Only instances have fields.");
+}
+
+#[test]
+fn classes_without_init_take_no_arguments() {
+    assert_eq!(runtime_error("class A {}\nA(1);"), ("Expected 0 arguments but got 1.".to_string(), 2));
+}
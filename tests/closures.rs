@@ -0,0 +1,85 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn closures_outlive_their_enclosing_call() {
+    let source = "
+fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+var a = counter();
+var b = counter();
+a(); a();
+print a();
+print b();
+";
+    assert_eq!(run_script(source).lines(), ["3", "1"]);
+}
+
+#[test]
+fn closures_share_captured_variables() {
+    let source = "
+var get; var set;
+fun make() {
+    var shared = 1;
+    fun g() { return shared; }
+    fun s(v) { shared = v; }
+    get = g; set = s;
+}
+make();
+set(42);
+print get();
+";
+    assert_eq!(run_script(source).lines(), ["42"]);
+}
+
+#[test]
+fn upvalues_are_captured_through_several_levels() {
+    let source = "
+fun outer() {
+    var x = 1;
+    fun middle() { fun inner() { x = x + 10; return x; } return inner; }
+    return middle();
+}
+var f = outer();
+print f();
+print f();
+";
+    assert_eq!(run_script(source).lines(), ["11", "21"]);
+}
+
+#[test]
+fn block_locals_are_closed_when_the_block_ends() {
+    let source = "
+var first; var second;
+{
+    var a = 1;
+    fun f() { return a; }
+    first = f;
+}
+{
+    var b = 2;
+    fun g() { return b; }
+    second = g;
+}
+print first() * 10 + second();
+";
+    assert_eq!(run_script(source).lines(), ["12"]);
+}
+
+#[test]
+fn loop_variables_are_captured_per_scope() {
+    let source = "
+var fs;
+{
+    var i = 0;
+    while (i < 1) {
+        var j = i + 5;
+        fun f() { return j; }
+        fs = f;
+        i = i + 1;
+    }
+}
+print fs();
+";
+    assert_eq!(run_script(source).lines(), ["5"]);
+}
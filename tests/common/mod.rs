@@ -0,0 +1,88 @@
+//! Helpers shared by the integration tests. Every test crate uses a different
+//! subset of them.
+#![allow(dead_code)]
+
+use rlox::driver::Driver;
+use rlox::value::Value;
+use rlox::vm::InterpretResult;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a run of the `rlox` binary printed, and how it exited.
+pub struct Run {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: Option<i32>,
+}
+
+impl Run {
+    fn new(output: Output) -> Run {
+        Run {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            code: output.status.code(),
+        }
+    }
+
+    pub fn lines(&self) -> Vec<&str> {
+        self.stdout.lines().collect()
+    }
+}
+
+/// Run `source` as a script file.
+pub fn run_script(source: &str) -> Run {
+    static SCRIPTS: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("rlox-test-{}-{}.lox", std::process::id(), SCRIPTS.fetch_add(1, Ordering::Relaxed));
+    let path = env::temp_dir().join(name);
+
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox")).arg(&path).output().unwrap();
+    fs::remove_file(&path).unwrap();
+    Run::new(output)
+}
+
+/// Feed `input` to the REPL, one line at a time.
+pub fn run_repl(input: &str) -> Run {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    Run::new(child.wait_with_output().unwrap())
+}
+
+/// Run `source` in a fresh session, expecting a runtime error, and return its
+/// message and line.
+pub fn runtime_error(source: &str) -> (String, usize) {
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret(source.to_string()), InterpretResult::RuntimeError), "{}", source);
+    let err = driver.last_error().expect("Expecting a runtime error");
+    (err.message.clone(), err.line)
+}
+
+/// Run `source` in the session of `driver`.
+pub fn run(driver: &mut Driver, source: &str) -> InterpretResult {
+    driver.interpret(source.to_string())
+}
+
+/// The global `name`, if it is defined and holds a number.
+pub fn number(driver: &Driver, name: &str) -> Option<f64> {
+    match driver.global(name) {
+        Some(Value::DOUBLE { data }) => Some(*data),
+        _ => None,
+    }
+}
+
+/// The global `name`, if it is defined and holds a boolean.
+pub fn boolean(driver: &Driver, name: &str) -> Option<bool> {
+    match driver.global(name) {
+        Some(Value::BOOL { data }) => Some(*data),
+        _ => None,
+    }
+}
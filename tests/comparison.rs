@@ -0,0 +1,36 @@
+mod common;
+
+use common::{boolean, run, run_script};
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+#[test]
+fn comparisons_follow_ieee_754() {
+    let mut driver = Driver::new();
+    let source = "var n = 0/0; var a = n >= n; var b = n <= n; var c = n != n; var d = n == n;";
+    assert!(matches!(run(&mut driver, source), InterpretResult::Ok));
+    // NaN is unordered, so it is neither at least nor at most itself.
+    assert_eq!(boolean(&driver, "a"), Some(false));
+    assert_eq!(boolean(&driver, "b"), Some(false));
+    assert_eq!(boolean(&driver, "c"), Some(true));
+    assert_eq!(boolean(&driver, "d"), Some(false));
+}
+
+#[test]
+fn not_equal_is_the_negation_of_equal() {
+    let source = "
+print 1 != 2;
+print 1 != 1;
+print \"a\" != \"a\";
+print \"a\" != \"b\";
+print nil != false;
+print nil != nil;
+";
+    assert_eq!(run_script(source).lines(), ["true", "false", "false", "true", "true", "false"]);
+}
+
+#[test]
+fn ordering_includes_equality() {
+    let run = run_script("print 2 >= 2; print 2 <= 2; print 1 >= 2; print 1 <= 0; print -1 <= 0;");
+    assert_eq!(run.lines(), ["true", "true", "false", "false", "true"]);
+}
@@ -0,0 +1,40 @@
+use rlox::compiler::Compiler;
+use rlox::globals::Globals;
+use rlox::heap::Heap;
+use rlox::obj::Function;
+use rlox::value::{Value, ValueArray};
+
+fn compile(source: String) -> Option<Function> {
+    let mut heap = Heap::new();
+    let mut globals = Globals::new();
+    Compiler::new(source, &mut heap, &mut globals).compile()
+}
+
+#[test]
+fn equal_constants_are_stored_once() {
+    let source = "var s = \"x\"; print s + \"x\" + \"x\"; print 1 + 1 + 1; print nil == nil; print true != true;";
+    let function = compile(source.to_string()).expect("Expecting the script to compile");
+
+    // "x", 1, nil, true
+    assert_eq!(function.chunk.value_array.data.len(), 4);
+}
+
+#[test]
+fn numbers_are_compared_by_bits() {
+    let mut constants = ValueArray::new();
+    let zero = constants.add_constant(Value::DOUBLE { data: 0.0 });
+    let negative_zero = constants.add_constant(Value::DOUBLE { data: -0.0 });
+    assert_ne!(zero, negative_zero);
+
+    let nan = constants.add_constant(Value::DOUBLE { data: f64::NAN });
+    assert_eq!(constants.add_constant(Value::DOUBLE { data: f64::NAN }), nan);
+}
+
+#[test]
+fn too_many_constants_is_a_compile_error() {
+    let source: String = (0..70_000).map(|n| format!("print {};", n)).collect();
+    assert!(compile(source).is_none());
+
+    let source: String = (0..1_000).map(|_| "print 1;").collect();
+    assert!(compile(source).is_some());
+}
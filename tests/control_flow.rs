@@ -0,0 +1,34 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn if_runs_one_branch() {
+    let source = "
+if (1 < 2) print 1; else print 2;
+if (1 > 2) print 3; else print 4;
+if (true) { print 5; }
+if (false) { print 6; }
+";
+    assert_eq!(run_script(source).lines(), ["1", "4", "5"]);
+}
+
+#[test]
+fn only_nil_and_false_are_falsey() {
+    let source = "
+if (nil) print 1; else print 2;
+if (false) print 3; else print 4;
+if (0) print 5; else print 6;
+if (\"\") print 7; else print 8;
+";
+    assert_eq!(run_script(source).lines(), ["2", "4", "5", "7"]);
+}
+
+#[test]
+fn else_binds_to_the_nearest_if() {
+    let source = "
+if (true) if (false) print 1; else print 2;
+if (false) if (true) print 3; else print 4;
+";
+    assert_eq!(run_script(source).lines(), ["2"]);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{run_script, runtime_error};
+
+#[test]
+fn calls_return_values() {
+    let source = "
+fun add(a, b) { return a + b; }
+fun nothing() {}
+fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+print add(1, 2);
+print nothing();
+print fib(15);
+var alias = add;
+print alias(add(1, 2), fib(15));
+";
+    let run = run_script(source);
+    assert_eq!(run.lines(), ["3", "nil", "610", "613"]);
+    assert_eq!(run.code, Some(0));
+}
+
+#[test]
+fn locals_are_per_call() {
+    let source = "
+fun count(n) { var local = n; if (n > 0) count(n - 1); return local; }
+print count(5);
+";
+    assert_eq!(run_script(source).lines(), ["5"]);
+}
+
+#[test]
+fn arity_mismatches_are_runtime_errors() {
+    let source = "fun f(a, b) { return a; }\nf(1);\nprint 9;";
+    assert_eq!(runtime_error(source), ("Expected 2 arguments but got 1.".to_string(), 2));
+
+    let source = "fun f() {}\n\nf(1, 2, 3);";
+    assert_eq!(runtime_error(source), ("Expected 0 arguments but got 3.".to_string(), 3));
+}
+
+#[test]
+fn only_functions_are_callable() {
+    for source in ["var a = 1; a();", "\"str\"();", "nil();", "true(1);"] {
+        assert_eq!(runtime_error(source).0, "Can only call functions and classes.");
+    }
+}
+
+#[test]
+fn errors_inside_calls_report_every_frame() {
+    let run = run_script("fun inner(x) { return x(); }\nfun outer() { return inner(1); }\nouter();");
+    assert_eq!(run.code, Some(70));
+    let trace: Vec<&str> = run.stderr.lines().collect();
+    let expected = ["Can only call functions and classes.", "[line 1] in inner()", "[line 2] in outer()", "[line 3] in script"];
+    assert_eq!(trace, expected);
+}
+
+#[test]
+fn return_outside_functions_is_a_compile_error() {
+    let run = run_script("return 1;");
+    assert_eq!(run.code, Some(65));
+    assert!(run.stdout.contains("Can't return from top-level code."), "{}", run.stdout);
+
+    assert_eq!(run_script("fun f() { return 1; }").code, Some(0));
+}
@@ -0,0 +1,73 @@
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+// Calling `nil` fails the run, which turns a wrong result into a runtime error.
+const PROGRAMS: &[&str] = &[
+    "fun make() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+     var c = make(); c(); if (c() != 2) nil();",
+    "class A { init(x) { this.x = x; } get() { return this.x; } }
+     class B < A { get() { return super.get() + 1; } }
+     var b = B(41); var m = b.get; if (m() != 42) nil();",
+    "var s = \"a\"; for (var i = 0; i < 50; i = i + 1) { s = s + \"b\"; }
+     if (s != \"a\" + \"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\") nil();",
+    "fun outer() { var xs = \"x\"; fun mid() { fun inner() { return xs + \"y\"; } return inner; } return mid(); }
+     var f = outer(); if (f() != \"xy\") nil();",
+];
+
+#[test]
+fn programs_survive_gc_stress() {
+    for program in PROGRAMS {
+        let mut driver = Driver::new();
+        driver.gc_stress(true);
+        assert!(matches!(driver.interpret(program.to_string()), InterpretResult::Ok), "{}", program);
+    }
+}
+
+#[test]
+fn globals_survive_collections_across_runs() {
+    let mut driver = Driver::new();
+    driver.gc_stress(true);
+    assert!(matches!(driver.interpret("var greeting = \"hel\" + \"lo\"; class P { hi() { return greeting; } } var p = P();".to_string()), InterpretResult::Ok));
+    assert!(matches!(driver.interpret("var junk = \"a\" + \"b\";".to_string()), InterpretResult::Ok));
+    assert!(matches!(driver.interpret("if (p.hi() != \"hello\") nil();".to_string()), InterpretResult::Ok));
+}
+
+#[test]
+fn cycles_are_collected() {
+    let mut driver = Driver::new();
+    driver.gc_stress(true);
+    let source = "class Node { init() { this.self = this; } }
+                  for (var i = 0; i < 1000; i = i + 1) { var n = Node(); fun f() { return f; } }";
+    assert!(matches!(driver.interpret(source.to_string()), InterpretResult::Ok));
+    assert!(driver.heap().live_objects() < 100);
+}
+
+#[test]
+fn constants_survive_compiling_large_scripts() {
+    // Compilation allocates every constant without collecting, and running the
+    // script then collects on every allocation.
+    let count = 500;
+    let mut source = String::new();
+    for i in 0..count {
+        source.push_str(&format!("fun f{}() {{ return \"s{}\"; }}\n", i, i));
+    }
+    let calls: Vec<String> = (0..count).map(|i| format!("f{}()", i)).collect();
+    let expected: String = (0..count).map(|i| format!("s{}", i)).collect();
+    source.push_str(&format!("var all = {};\nif (all != \"{}\") nil();", calls.join(" + "), expected));
+
+    let mut driver = Driver::new();
+    driver.gc_stress(true);
+    assert!(matches!(driver.interpret(source), InterpretResult::Ok));
+}
+
+#[test]
+fn equal_strings_share_one_object() {
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret("var s = \"ab\";".to_string()), InterpretResult::Ok));
+    let before = driver.heap().live_objects();
+
+    // Without any collection, rebuilding an existing string allocates nothing.
+    let source = "for (var i = 0; i < 1000; i = i + 1) { var t = \"a\" + \"b\"; if (t != s) nil(); }";
+    assert!(matches!(driver.interpret(source.to_string()), InterpretResult::Ok));
+    assert!(driver.heap().live_objects() < before + 10);
+}
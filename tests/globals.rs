@@ -0,0 +1,36 @@
+mod common;
+
+use common::{number, run};
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+#[test]
+fn globals_are_read_and_written_by_slot() {
+    let mut driver = Driver::new();
+    assert!(matches!(run(&mut driver, "var a = 1; var b = 2; a = a + b; var b = 10;"), InterpretResult::Ok));
+    assert_eq!(number(&driver, "a"), Some(3.0));
+    assert_eq!(number(&driver, "b"), Some(10.0));
+    assert!(driver.global("c").is_none());
+}
+
+#[test]
+fn functions_see_globals_defined_later() {
+    let mut driver = Driver::new();
+    assert!(matches!(run(&mut driver, "fun f() { return later; }"), InterpretResult::Ok));
+    assert!(matches!(run(&mut driver, "var r = f();"), InterpretResult::RuntimeError));
+    assert_eq!(driver.last_error().map(|err| err.message.as_str()), Some("Undefined variable: later"));
+
+    assert!(matches!(run(&mut driver, "var later = \"now\"; var r = f();"), InterpretResult::Ok));
+    let r = driver.global("r").cloned().and_then(|v| driver.heap().as_string(&v).map(str::to_string));
+    assert_eq!(r.as_deref(), Some("now"));
+}
+
+#[test]
+fn undefined_variables_are_named() {
+    let mut driver = Driver::new();
+    assert!(matches!(run(&mut driver, "print missing;"), InterpretResult::RuntimeError));
+    assert_eq!(driver.last_error().map(|err| err.message.as_str()), Some("Undefined variable: missing"));
+
+    assert!(matches!(run(&mut driver, "missing = 1;"), InterpretResult::RuntimeError));
+    assert_eq!(driver.last_error().map(|err| err.message.as_str()), Some("Undefined variable: missing"));
+}
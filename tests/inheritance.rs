@@ -0,0 +1,57 @@
+mod common;
+
+use common::{run_repl, run_script, runtime_error};
+
+const SHAPES: &str = "
+class Shape {
+    init(name) { this.name = name; }
+    area() { return 0; }
+    describe() { return this.area() * 10; }
+}
+class Square < Shape {
+    init(side) { super.init(\"square\"); this.side = side; }
+    area() { return this.side * this.side; }
+    base() { return super.area(); }
+    bound() { var m = super.describe; return m; }
+}
+var s = Square(3);
+";
+
+#[test]
+fn subclasses_inherit_and_override_methods() {
+    let source = format!("{}print s.area();\nprint s.describe();\nprint s.base();\nprint s.bound()();\nprint s.name == \"square\";", SHAPES);
+    // Inherited methods dispatch to overrides through this.
+    assert_eq!(run_script(&source).lines(), ["9", "90", "0", "90", "true"]);
+}
+
+#[test]
+fn super_calls_use_the_invoke_fast_path() {
+    // The REPL prints the code it compiles.
+    let run = run_repl(&SHAPES.replace('\n', " "));
+    assert!(run.stdout.contains("SUPER_INVOKE"), "{}", run.stdout);
+    // Reading a method off super without calling it binds it instead.
+    assert!(run.stdout.contains("GET_SUPER"), "{}", run.stdout);
+}
+
+#[test]
+fn superclasses_must_be_classes() {
+    let source = "var NotClass = 1;\nclass A < NotClass {}";
+    assert_eq!(runtime_error(source), ("Superclass must be a class.".to_string(), 2));
+}
+
+#[test]
+fn misused_inheritance_is_a_compile_error() {
+    let errors = [
+        ("class A < A {}", "A class can't inherit from itself."),
+        ("fun f() { return super.m(); }", "Can't use 'super' outside of a class."),
+        ("print super.m;", "Can't use 'super' outside of a class."),
+        ("class A { m() { return super.m(); } }", "Can't use 'super' in a class with no superclass."),
+    ];
+    for (source, message) in errors {
+        let run = run_script(source);
+        assert_eq!(run.code, Some(65), "{}", source);
+        assert!(run.stdout.contains(message), "{}", run.stdout);
+    }
+
+    assert_eq!(run_script("class A { m() {} } class B < A { m() { return super.m(); } }").code, Some(0));
+}
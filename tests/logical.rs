@@ -0,0 +1,37 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn and_or_return_an_operand() {
+    let source = "
+print nil or 3;
+print 1 or 2;
+print 1 and 4;
+print false and 5;
+print nil and 6;
+";
+    assert_eq!(run_script(source).lines(), ["3", "1", "4", "false", "nil"]);
+}
+
+#[test]
+fn and_or_short_circuit() {
+    let source = "
+var a = 0;
+false and (a = 1);
+print a;
+true or (a = 2);
+print a;
+true and (a = 3);
+print a;
+nil or (a = 4);
+print a;
+";
+    assert_eq!(run_script(source).lines(), ["0", "0", "3", "4"]);
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    let run = run_script("print false and false or true;\nprint true or true and false;");
+    assert_eq!(run.lines(), ["true", "true"]);
+}
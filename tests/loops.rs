@@ -0,0 +1,43 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn while_loops_until_the_condition_is_false() {
+    let run = run_script("var n = 0;\nwhile (n < 3) { print n; n = n + 1; }\nprint n * 10;");
+    assert_eq!(run.lines(), ["0", "1", "2", "30"]);
+
+    let run = run_script("while (false) print 1; print 2;");
+    assert_eq!(run.lines(), ["2"]);
+}
+
+#[test]
+fn for_loops_run_their_clauses_in_order() {
+    let source = "
+var sum = 0;
+for (var i = 1; i < 5; i = i + 1) sum = sum + i;
+print sum;
+";
+    assert_eq!(run_script(source).lines(), ["10"]);
+}
+
+#[test]
+fn for_clauses_are_optional() {
+    let source = "
+var i = 0;
+for (; i < 3;) { print i; i = i + 1; }
+for (var j = 5; j < 7;) { print j; j = j + 1; }
+for (i = 10; i < 12; i = i + 1) print i;
+";
+    assert_eq!(run_script(source).lines(), ["0", "1", "2", "5", "6", "10", "11"]);
+}
+
+#[test]
+fn for_variables_are_scoped_to_the_loop() {
+    let source = "
+var i = 100;
+for (var i = 0; i < 2; i = i + 1) { var x = i * 2; print x; }
+print i;
+";
+    assert_eq!(run_script(source).lines(), ["0", "2", "100"]);
+}
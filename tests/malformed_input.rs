@@ -0,0 +1,177 @@
+//! Malformed programs must be reported as compile or runtime errors, never
+//! crash the interpreter.
+
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+const CORPUS: &[&str] = &[
+    "",
+    "1",
+    "print",
+    "print 1",
+    "print 1.;",
+    "print 1.5.5;",
+    "print 99999999999999999999999999999999999999;",
+    "\"unterminated",
+    "var x = é;",
+    "print \"héllo\" + \"wörld\";",
+    "@#$%^&",
+    "(((",
+    ")))",
+    "{",
+    "}",
+    "{{{{{",
+    "var;",
+    "var = 1;",
+    "var x = ;",
+    "1 = 2;",
+    "a + b = c;",
+    "fun",
+    "fun f(",
+    "fun f(a b) {}",
+    "fun f() { return",
+    "return 1;",
+    "class",
+    "class A { 1 }",
+    "class A < A {}",
+    "class A < { }",
+    "class A { init() { return 1; } }",
+    "this;",
+    "super.x;",
+    "class A { f() { super.f(); } }",
+    "f(1,",
+    "a.b.c =",
+    "if",
+    "if (true",
+    "while (",
+    "for (;;",
+    "for (var i = 0; i < 1; i = i + 1",
+    "print -true;",
+    "print !nil;",
+    "print 1 + \"a\";",
+    "print \"a\" + 1;",
+    "print nil < 1;",
+    "print undefined;",
+    "undefined = 1;",
+    "print clock(1);",
+    "print clock + clock;",
+    "nil();",
+    "\"str\"();",
+    "var a = 1; a.field;",
+    "var a = 1; a.field = 2;",
+    "var a = 1; a.method();",
+    "class A {} A(1, 2);",
+    "class A {} print A().missing;",
+    "class A {} A().missing();",
+    "var NotAClass = 1; class B < NotAClass {}",
+    "fun f(a) {} f();",
+    "fun f() { f(); } f();",
+    "fun f(n) { return f(n + 1) + 1; } f(0);",
+    "class A { f() { return this.f(); } } A().f();",
+];
+
+fn interpret(source: &str) -> InterpretResult {
+    let mut driver = Driver::new();
+    driver.interpret(source.to_string())
+}
+
+#[test]
+fn corpus_never_panics() {
+    for source in CORPUS {
+        interpret(source);
+    }
+}
+
+#[test]
+fn runtime_errors_are_reported() {
+    for source in ["print -true;", "print 1 + \"a\";", "print undefined;", "nil();", "fun f() { f(); } f();"] {
+        assert!(matches!(interpret(source), InterpretResult::RuntimeError), "{}", source);
+    }
+}
+
+#[test]
+fn deep_nesting_is_a_compile_error() {
+    let depth = 100_000;
+    let sources = [
+        "(".repeat(depth) + "1" + &")".repeat(depth) + ";",
+        "-".repeat(depth) + "1;",
+        "{".repeat(depth) + &"}".repeat(depth),
+        "if (true) ".repeat(depth) + "print 1;",
+        "fun f() {".repeat(depth) + &"}".repeat(depth),
+    ];
+
+    for source in &sources {
+        assert!(matches!(interpret(source), InterpretResult::CompileError));
+    }
+}
+
+#[test]
+fn session_survives_errors() {
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret("var x = 1;".to_string()), InterpretResult::Ok));
+    assert!(matches!(driver.interpret("print -\"x\";".to_string()), InterpretResult::RuntimeError));
+    assert!(matches!(driver.interpret("print (;".to_string()), InterpretResult::CompileError));
+    assert!(matches!(driver.interpret("print x;".to_string()), InterpretResult::Ok));
+}
+
+/// Deterministic xorshift generator, so that failures are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Loop keywords are left out so that generated programs always terminate.
+const TOKENS: &[&str] = &[
+    "(", ")", "{", "}", ",", ".", "-", "+", ";", "/", "*", "!", "!=", "=", "==",
+    ">", ">=", "<", "<=", "a", "b", "f", "A", "\"s\"", "1", "2.5", "and", "class",
+    "else", "false", "fun", "if", "nil", "or", "print", "return", "super", "this",
+    "true", "var", "init", "clock",
+];
+
+#[test]
+fn random_token_streams_never_panic() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    for _ in 0..2000 {
+        let len = rng.below(40);
+        let source: Vec<&str> = (0..len).map(|_| TOKENS[rng.below(TOKENS.len())]).collect();
+        interpret(&source.join(" "));
+    }
+}
+
+const SEEDS: &[&str] = &[
+    "fun make() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } var c = make(); print c();",
+    "class A { init(x) { this.x = x; } get() { return this.x; } } class B < A { get() { return super.get() + 1; } } print B(1).get();",
+    "var s = \"a\"; { var t = s + \"b\"; print t == \"ab\"; } if (s != nil and !false) print s; else print nil;",
+];
+
+#[test]
+fn mutated_programs_never_panic() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    for _ in 0..2000 {
+        let mut source = SEEDS[rng.below(SEEDS.len())].as_bytes().to_vec();
+        for _ in 0..1 + rng.below(4) {
+            let at = rng.below(source.len());
+            match rng.below(3) {
+                0 => { source.remove(at); },
+                1 => source.insert(at, b"(){};.=+-!\"a1"[rng.below(13)]),
+                _ => {
+                    let other = rng.below(source.len());
+                    source.swap(at, other);
+                },
+            }
+        }
+        interpret(&String::from_utf8_lossy(&source));
+    }
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{run_script, runtime_error};
+
+#[test]
+fn initializers_receive_the_arguments() {
+    let source = "
+class Pair {
+    init(a, b) { this.a = a; this.b = b; }
+    sum() { return this.a + this.b; }
+}
+var p = Pair(3, 4);
+print p.sum();
+var again = p.init(1, 2);
+print again == p;
+print p.sum();
+";
+    // Calling init directly returns the instance, re-initialized.
+    assert_eq!(run_script(source).lines(), ["7", "true", "3"]);
+}
+
+#[test]
+fn bound_methods_remember_their_receiver() {
+    let source = "
+class Counter {
+    init() { this.n = 0; }
+    bump() { this.n = this.n + 1; return this.n; }
+}
+var c = Counter();
+var bump = c.bump;
+c = nil;
+bump();
+print bump();
+";
+    assert_eq!(run_script(source).lines(), ["2"]);
+}
+
+#[test]
+fn this_is_captured_by_closures_in_methods() {
+    let source = "
+class Box {
+    init(v) { this.v = v; }
+    getter() { fun get() { return this.v; } return get; }
+}
+print Box(5).getter()();
+";
+    assert_eq!(run_script(source).lines(), ["5"]);
+}
+
+#[test]
+fn fields_shadow_methods() {
+    let source = "
+class A { m() { return 1; } }
+fun two() { return 2; }
+var a = A();
+print a.m();
+a.m = two;
+print a.m();
+";
+    assert_eq!(run_script(source).lines(), ["1", "2"]);
+}
+
+#[test]
+fn method_errors_are_runtime_errors() {
+    let source = "class A {}\nA().missing();";
+    assert_eq!(runtime_error(source), ("Undefined property 'missing'.".to_string(), 2));
+
+    assert_eq!(runtime_error("var n = 1; n.m();").0, "Only instances have methods.");
+    let source = "class A { init(x) {} }\nA();";
+    assert_eq!(runtime_error(source), ("Expected 1 arguments but got 0.".to_string(), 2));
+}
+
+#[test]
+fn initializers_cannot_return_values() {
+    let run = run_script("class A { init() { return 1; } }");
+    assert_eq!(run.code, Some(65));
+    assert!(run.stdout.contains("Can't return a value from an initializer."), "{}", run.stdout);
+
+    let run = run_script("class A { init() { return; } } print A();");
+    assert_eq!(run.lines(), ["A instance"]);
+}
+
+#[test]
+fn this_outside_classes_is_a_compile_error() {
+    for source in ["print this;", "fun f() { return this; }"] {
+        let run = run_script(source);
+        assert_eq!(run.code, Some(65), "{}", source);
+        assert!(run.stdout.contains("Can't use 'this' outside of a class."), "{}", run.stdout);
+    }
+}
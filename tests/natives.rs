@@ -0,0 +1,39 @@
+use rlox::driver::Driver;
+use rlox::heap::Heap;
+use rlox::value::Value;
+use rlox::vm::InterpretResult;
+
+fn upper(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s = heap.as_string(&args[0]).ok_or("upper() expects a string.")?.to_uppercase();
+    Ok(Value::OBJ { data: heap.intern(s) })
+}
+
+fn driver() -> Driver {
+    let mut driver = Driver::new();
+    driver.define_native("upper", 1, upper);
+    driver
+}
+
+#[test]
+fn natives_read_and_return_strings() {
+    for stress in [false, true] {
+        let mut driver = driver();
+        driver.gc_stress(stress);
+        let source = "var shout = upper(\"hello, \" + \"world\"); var same = shout == \"HELLO, WORLD\";";
+        assert!(matches!(driver.interpret(source.to_string()), InterpretResult::Ok));
+
+        let shout = driver.global("shout").and_then(|v| driver.heap().as_string(v));
+        assert_eq!(shout, Some("HELLO, WORLD"));
+        // Results are interned like any other string.
+        assert!(matches!(driver.global("same"), Some(Value::BOOL { data: true })));
+    }
+}
+
+#[test]
+fn native_errors_are_runtime_errors() {
+    let mut driver = driver();
+    assert!(matches!(driver.interpret("\n\nupper(1);".to_string()), InterpretResult::RuntimeError));
+
+    let err = driver.last_error().expect("Expecting a runtime error");
+    assert_eq!((err.message.as_str(), err.line), ("upper() expects a string.", 3));
+}
@@ -0,0 +1,16 @@
+mod common;
+
+use common::run_repl;
+
+#[test]
+fn globals_persist_across_lines() {
+    let run = run_repl("var a = 40;\nvar b = a + 2;\nprint b * 100;\n");
+    assert!(run.lines().contains(&"4200"), "{}", run.stdout);
+}
+
+#[test]
+fn errors_do_not_end_the_session() {
+    let run = run_repl("var a = 1234;\nprint (;\nprint a;\n");
+    assert!(run.lines().contains(&"1234"), "{}", run.stdout);
+    assert_eq!(run.code, Some(0));
+}
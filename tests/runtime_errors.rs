@@ -0,0 +1,25 @@
+mod common;
+
+use common::{run_script, runtime_error};
+
+#[test]
+fn not_takes_booleans_and_nil() {
+    assert_eq!(run_script("print !nil; print !true; print !false;").lines(), ["true", "false", "true"]);
+
+    assert_eq!(runtime_error("var a = 1;\nprint !a;"), ("Expecting operand of type boolean or nil".to_string(), 2));
+    assert_eq!(runtime_error("print !\"s\";").0, "Expecting operand of type boolean or nil");
+}
+
+#[test]
+fn type_errors_abort_execution() {
+    let run = run_script("print 1;\nprint -nil;\nprint 2;");
+    assert_eq!(run.code, Some(70));
+    assert_eq!(run.lines(), ["1"]);
+    assert_eq!(run.stderr.lines().collect::<Vec<_>>(), ["Expecting operand of type number", "[line 2] in script"]);
+}
+
+#[test]
+fn undefined_variables_abort_execution() {
+    assert_eq!(runtime_error("print 1;\n\nprint missing;"), ("Undefined variable: missing".to_string(), 3));
+    assert_eq!(runtime_error("missing = 1;").0, "Undefined variable: missing");
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::run_script;
+
+#[test]
+fn locals_shadow_outer_variables_until_their_block_ends() {
+    let source = "
+var a = 1;
+{
+    var a = 2;
+    {
+        var b = a + 1;
+        var a = b;
+        print a;
+    }
+    print a;
+    a = 20;
+    print a;
+}
+print a;
+";
+    let run = run_script(source);
+    assert_eq!(run.lines(), ["3", "2", "20", "1"]);
+}
+
+#[test]
+fn locals_are_freed_when_their_block_ends() {
+    // The slot of `b` is reused by `c`, which must not see its value.
+    let run = run_script("{ { var b = 5; } var c = 7; print c; } { var d = 8; print d; }");
+    assert_eq!(run.lines(), ["7", "8"]);
+}
+
+#[test]
+fn misused_locals_are_compile_errors() {
+    let run = run_script("{\n    var a = 1;\n    var a = 2;\n}");
+    assert_eq!(run.code, Some(65));
+    assert!(run.stdout.contains("[line 3] Error at a : Already a variable with this name in this scope."), "{}", run.stdout);
+
+    let run = run_script("var a = 1;\n{\n    var a = a;\n}");
+    assert_eq!(run.code, Some(65));
+    assert!(run.stdout.contains("Can't read local variable in its own initializer."), "{}", run.stdout);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::{number, run_script};
+use rlox::driver::Driver;
+use rlox::serialize;
+use rlox::vm::InterpretResult;
+
+const PROGRAM: &str = "
+var total = 0;
+fun adder(n) { fun add(x) { return x + n; } return add; }
+class Counter {
+    init() { this.count = 0; }
+    bump() { this.count = this.count + 1; return this; }
+}
+var c = Counter();
+for (var i = 0; i < 10; i = i + 1) {
+    if (i > 4) total = adder(i)(total); else c.bump();
+}
+var result = total + c.count;
+var greeting = \"hello\" + \" \" + \"world\";
+";
+
+fn compile(source: &str) -> Vec<u8> {
+    Driver::new().compile(source.to_string()).expect("Expecting the program to compile")
+}
+
+fn assert_result(driver: &Driver) {
+    assert_eq!(number(driver, "result"), Some(40.0));
+    let greeting = driver.global("greeting").and_then(|v| driver.heap().as_string(v));
+    assert_eq!(greeting, Some("hello world"));
+}
+
+#[test]
+fn compiled_scripts_run_in_a_fresh_session() {
+    let bytes = compile(PROGRAM);
+
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret_compiled(&bytes), Ok(InterpretResult::Ok)));
+    assert_result(&driver);
+}
+
+#[test]
+fn globals_are_relocated_on_load() {
+    let bytes = compile(PROGRAM);
+
+    // Shift every global slot, past the width of one-byte operands.
+    let mut driver = Driver::new();
+    let padding: String = (0..300).map(|n| format!("var pad{} = {};", n, n)).collect();
+    assert!(matches!(driver.interpret(padding), InterpretResult::Ok));
+
+    assert!(matches!(driver.interpret_compiled(&bytes), Ok(InterpretResult::Ok)));
+    assert_result(&driver);
+    assert_eq!(number(&driver, "pad299"), Some(299.0));
+}
+
+#[test]
+fn runtime_errors_keep_their_lines() {
+    let bytes = compile("var a = 1;\n\nprint a + nil;");
+
+    let mut driver = Driver::new();
+    assert!(matches!(driver.interpret_compiled(&bytes), Ok(InterpretResult::RuntimeError)));
+    assert_eq!(driver.last_error().map(|err| err.line), Some(3));
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let bytes = compile(PROGRAM);
+    let mut driver = Driver::new();
+
+    let mut corrupted = bytes.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    let err = driver.interpret_compiled(&corrupted).expect_err("Expecting a checksum mismatch");
+    assert_eq!(err.message, "checksum mismatch.");
+
+    let mut version = bytes.clone();
+    version[4] = 99;
+    assert!(driver.interpret_compiled(&version).is_err());
+
+    for len in [0, 4, 10, bytes.len() / 2] {
+        assert!(driver.interpret_compiled(&bytes[..len]).is_err());
+    }
+}
+
+#[test]
+fn sources_are_not_mistaken_for_compiled_scripts() {
+    let source = "var LOXCount = 1;\nLOXCount;\n";
+    assert!(!serialize::is_compiled(source.as_bytes()));
+
+    let run = run_script(source);
+    assert_eq!(run.code, Some(0), "{}", run.stdout);
+}
@@ -0,0 +1,60 @@
+use rlox::driver::Driver;
+use rlox::vm::InterpretResult;
+
+const RECURSE: &str = "fun f(n) { if (n == 0) return 0; return f(n - 1) + 1; }";
+
+#[test]
+fn deep_recursion_overflows_cleanly() {
+    let mut driver = Driver::new();
+    let res = driver.interpret(format!("{} f(100000);", RECURSE));
+    assert!(matches!(res, InterpretResult::RuntimeError));
+
+    let err = driver.last_error().expect("Expecting a runtime error");
+    assert_eq!(err.message, "Stack overflow.");
+    assert_eq!(err.trace.last().and_then(|frame| frame.function.clone()), None);
+    assert_eq!(err.trace.first().map(|frame| frame.function.as_deref()), Some(Some("f")));
+}
+
+#[test]
+fn repeated_frames_are_collapsed() {
+    let mut driver = Driver::new();
+    driver.set_max_frames(64);
+    let source = format!("{}\nfun g() {{ return f(100); }}\ng();", RECURSE);
+    assert!(matches!(driver.interpret(source), InterpretResult::RuntimeError));
+
+    let err = driver.last_error().expect("Expecting a runtime error");
+    let frames: Vec<(Option<&str>, usize, usize)> = err.trace.iter()
+        .map(|frame| (frame.function.as_deref(), frame.line, frame.repeats))
+        .collect();
+    assert_eq!(frames, [(Some("f"), 1, 61), (Some("g"), 2, 0), (None, 3, 0)]);
+    assert_eq!(err.to_string().lines().collect::<Vec<_>>(), [
+        "Stack overflow.",
+        "[line 1] in f()",
+        "... 61 more frames",
+        "[line 2] in g()",
+        "[line 3] in script",
+    ]);
+}
+
+#[test]
+fn frame_limit_is_configurable() {
+    let mut driver = Driver::new();
+    driver.set_max_frames(2000);
+    driver.set_max_stack(1 << 20);
+    assert!(matches!(driver.interpret(format!("{} f(1000);", RECURSE)), InterpretResult::Ok));
+
+    driver.set_max_frames(10);
+    assert!(matches!(driver.interpret("f(20);".to_string()), InterpretResult::RuntimeError));
+}
+
+#[test]
+fn stack_limit_is_configurable() {
+    let mut driver = Driver::new();
+    driver.set_max_stack(8);
+    let source = "print 1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + 1))))))));";
+    assert!(matches!(driver.interpret(source.to_string()), InterpretResult::RuntimeError));
+    assert_eq!(driver.last_error().map(|err| err.message.as_str()), Some("Stack overflow."));
+
+    driver.set_max_stack(64);
+    assert!(matches!(driver.interpret(source.to_string()), InterpretResult::Ok));
+}
@@ -0,0 +1,137 @@
+use rlox::chunk::Inst;
+use rlox::compiler::Compiler;
+use rlox::driver::Driver;
+use rlox::globals::Globals;
+use rlox::heap::Heap;
+use rlox::obj::{Function, UpvalueDesc};
+use rlox::serialize;
+use rlox::value::Value;
+use rlox::verify::{verify, VerifyError};
+
+const PROGRAMS: &[&str] = &[
+    "var a = 1; { var b = a + 2; print b; } a = a * 3;",
+    "for (var i = 0; i < 3; i = i + 1) { if (i == 1 and true or false) print i; else print -i; }",
+    "var n = 0; while (n < 10) { n = n + 1; if (!(n > 5)) { var t = n; print t; } }",
+    "fun make() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } var c = make(); print c();",
+    "fun outer() { var x = 1; fun middle() { fun inner() { return x; } return inner; } return middle()(); }",
+    "class A { init(x) { this.x = x; } get() { return this.x; } }
+     class B < A { init(x) { super.init(x); } get() { return super.get() + 1; } other() { var m = super.get; return m(); } }
+     print B(1).get(); print B(2).other();",
+    "fun f(a, b, c) { if (a) return b; else { var d = c; return d; } } print f(true, 1, 2);",
+];
+
+fn script(insts: &[Inst], constants: Vec<Value>) -> Function {
+    let mut function = Function::new(None);
+    for constant in constants {
+        function.chunk.value_array.add_constant(constant);
+    }
+    for &inst in insts {
+        function.chunk.write(inst, 1);
+    }
+    function
+}
+
+fn check(insts: &[Inst], constants: Vec<Value>) -> Result<(), VerifyError> {
+    verify(&Heap::new(), &Globals::new(), &script(insts, constants))
+}
+
+fn rejected(insts: &[Inst], constants: Vec<Value>) -> String {
+    check(insts, constants).expect_err("Expecting the chunk to be rejected").message
+}
+
+#[test]
+fn compiler_output_is_accepted() {
+    for source in PROGRAMS {
+        let mut heap = Heap::new();
+        let mut globals = Globals::new();
+        let function = Compiler::new(source.to_string(), &mut heap, &mut globals).compile()
+            .expect("Expecting the program to compile");
+
+        if let Err(err) = verify(&heap, &globals, &function) {
+            panic!("{}: {}", source, err);
+        }
+    }
+}
+
+#[test]
+fn well_formed_chunks_are_accepted() {
+    let nil = || vec![Value::NIL];
+    assert!(check(&[Inst::CONSTANT { idx: 0 }, Inst::RETURN], nil()).is_ok());
+
+    // if (nil) print nil; return nil;
+    let branch = [
+        Inst::CONSTANT { idx: 0 },
+        Inst::OP_JUMP_IF_FALSE { offset: 7 },
+        Inst::OP_POP,
+        Inst::CONSTANT { idx: 0 },
+        Inst::OP_PRINT,
+        Inst::OP_JUMP { offset: 1 },
+        Inst::OP_POP,
+        Inst::CONSTANT { idx: 0 },
+        Inst::RETURN,
+    ];
+    assert!(check(&branch, nil()).is_ok());
+}
+
+#[test]
+fn unsafe_chunks_are_rejected() {
+    let nil = || vec![Value::NIL];
+
+    let message = rejected(&[Inst::CONSTANT { idx: 1 }, Inst::RETURN], nil());
+    assert_eq!(message, "Constant index 1 out of range, the chunk has 1 constants.");
+
+    let message = rejected(&[Inst::CONSTANT { idx: 0 }, Inst::OP_ADD, Inst::RETURN], nil());
+    assert_eq!(message, "Instruction pops 2 values, but only 1 are above slot zero.");
+
+    let message = rejected(&[Inst::CONSTANT { idx: 0 }, Inst::OP_PRINT], nil());
+    assert_eq!(message, "Chunk does not end in RETURN.");
+
+    let message = rejected(&[Inst::OP_GET_LOCAL { slot: 1 }, Inst::RETURN], nil());
+    assert_eq!(message, "Local slot 1 out of range, the frame holds 1 values.");
+
+    let message = rejected(&[Inst::OP_GET_GLOBAL { slot: 0 }, Inst::RETURN], nil());
+    assert_eq!(message, "Global slot 0 out of range, the VM has 0 globals.");
+
+    let message = rejected(&[Inst::OP_GET_PROPERTY { name_idx: 0 }, Inst::RETURN], nil());
+    assert_eq!(message, "Constant 0 is not a string, as names must be.");
+
+    let message = rejected(&[Inst::CONSTANT { idx: 0 }, Inst::OP_JUMP { offset: 1 }, Inst::RETURN], nil());
+    assert_eq!(message, "Jump to offset 6, which is not the start of an instruction.");
+
+    // The push is skipped when the condition is false.
+    let unbalanced = [
+        Inst::CONSTANT { idx: 0 },
+        Inst::OP_JUMP_IF_FALSE { offset: 2 },
+        Inst::CONSTANT { idx: 0 },
+        Inst::RETURN,
+    ];
+    let message = rejected(&unbalanced, nil());
+    assert_eq!(message, "Stack height at offset 7 is 2 on one path and 3 on another.");
+
+    let mut truncated = script(&[Inst::CONSTANT { idx: 0 }, Inst::RETURN], nil());
+    truncated.chunk.code.push(255);
+    let err = verify(&Heap::new(), &Globals::new(), &truncated).expect_err("Expecting the chunk to be rejected");
+    assert_eq!(err.to_string(), "Invalid or truncated instruction with opcode 255. (at offset 3 in script)");
+}
+
+#[test]
+fn loader_rejects_unsafe_chunks() {
+    let function = script(&[Inst::OP_POP, Inst::RETURN], vec![]);
+    let bytes = serialize::serialize(&Heap::new(), &Globals::new(), &function);
+
+    let err = Driver::new().interpret_compiled(&bytes).expect_err("Expecting the script to be rejected");
+    assert!(err.message.starts_with("Instruction pops 1 values"), "{}", err);
+}
+
+#[test]
+fn scripts_with_arguments_or_upvalues_are_rejected() {
+    let mut function = script(&[Inst::OP_GET_LOCAL { slot: 3 }, Inst::RETURN], vec![]);
+    function.arity = 3;
+    let err = verify(&Heap::new(), &Globals::new(), &function).expect_err("Expecting the script to be rejected");
+    assert_eq!(err.message, "The script takes 3 arguments, but is run with none.");
+
+    let mut function = script(&[Inst::OP_GET_UPVALUE { idx: 0 }, Inst::RETURN], vec![]);
+    function.upvalues.push(UpvalueDesc { is_local: true, index: 0 });
+    let err = verify(&Heap::new(), &Globals::new(), &function).expect_err("Expecting the script to be rejected");
+    assert_eq!(err.message, "The script captures 1 upvalues, but is run without any.");
+}